@@ -0,0 +1,367 @@
+use std::net::IpAddr;
+
+use serde::Deserialize;
+
+// Rules are written as `host[:ports]`, where host is `*`, an IP address, a
+// CIDR range (IPv6 in brackets when a port follows, e.g. `[fd00::/8]:22`) or a
+// hostname that may contain `*` wildcards, and ports is a single port, a
+// `low-high` range or `*`. A target is refused if any deny rule matches it. If
+// there are no allow rules everything else is permitted, otherwise an allow
+// rule has to match.
+#[derive(Debug, Default, Deserialize)]
+pub struct AclConfig {
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+pub struct Acl {
+    allow: Vec<Rule>,
+    deny: Vec<Rule>,
+}
+
+struct Rule {
+    host: HostPattern,
+    ports: (u16, u16),
+}
+
+enum HostPattern {
+    Any,
+    Cidr(IpAddr, u8),
+    Name(String),
+}
+
+impl Acl {
+    pub fn from_config(config: &AclConfig) -> anyhow::Result<Self> {
+        let parse_all = |rules: &[String]| -> anyhow::Result<Vec<Rule>> {
+            rules.iter().map(|r| Rule::parse(r)).collect()
+        };
+        Ok(Acl {
+            allow: parse_all(&config.allow)?,
+            deny: parse_all(&config.deny)?,
+        })
+    }
+
+    // Checks a target as written by the client, before it is resolved. A
+    // hostname that only IP-based allow rules could permit is let through
+    // here and decided by check_resolved at dial time.
+    pub fn check_target(&self, host: &str, port: u16) -> Result<(), String> {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return self.check_resolved(host, ip, port);
+        }
+
+        let name = normalize_name(host);
+        if let Some(rule) = self.deny.iter().find(|r| r.matches_name(&name, port)) {
+            return Err(format!("{}:{} denied by rule {}", host, port, rule));
+        }
+        if self.allow.is_empty()
+            || self.allow.iter().any(|r| r.matches_name(&name, port))
+            || self.allow.iter().any(|r| r.is_ip_based() && r.matches_port(port))
+        {
+            return Ok(());
+        }
+        Err(format!("{}:{} is not in the allow list", host, port))
+    }
+
+    // Checks one resolved address of a target. `host` is the name the client
+    // asked for, so hostname rules keep applying after resolution.
+    pub fn check_resolved(&self, host: &str, ip: IpAddr, port: u16) -> Result<(), String> {
+        let name = normalize_name(host);
+        if let Some(rule) = self
+            .deny
+            .iter()
+            .find(|r| r.matches_name(&name, port) || r.matches_ip(ip, port))
+        {
+            return Err(format!("{} port {} denied by rule {}", describe(host, ip), port, rule));
+        }
+        if self.allow.is_empty()
+            || self
+                .allow
+                .iter()
+                .any(|r| r.matches_name(&name, port) || r.matches_ip(ip, port))
+        {
+            return Ok(());
+        }
+        Err(format!("{} port {} is not in the allow list", describe(host, ip), port))
+    }
+}
+
+impl Rule {
+    fn parse(rule: &str) -> anyhow::Result<Self> {
+        let invalid = || anyhow::anyhow!("Invalid ACL rule: {}", rule);
+
+        let (host, ports) = if let Some(rest) = rule.strip_prefix('[') {
+            let close = rest.find(']').ok_or_else(invalid)?;
+            let ports = match &rest[close + 1..] {
+                "" => None,
+                p => Some(p.strip_prefix(':').ok_or_else(invalid)?),
+            };
+            (&rest[..close], ports)
+        } else if rule.matches(':').count() > 1 {
+            // Bare IPv6 address or range without a port.
+            (rule, None)
+        } else {
+            match rule.split_once(':') {
+                Some((host, ports)) => (host, Some(ports)),
+                None => (rule, None),
+            }
+        };
+
+        let ports = match ports {
+            None | Some("*") => (0, u16::MAX),
+            Some(p) => match p.split_once('-') {
+                Some((low, high)) => (
+                    low.parse().map_err(|_| invalid())?,
+                    high.parse().map_err(|_| invalid())?,
+                ),
+                None => {
+                    let port = p.parse().map_err(|_| invalid())?;
+                    (port, port)
+                }
+            },
+        };
+        if ports.0 > ports.1 {
+            return Err(invalid());
+        }
+
+        let host = if host == "*" {
+            HostPattern::Any
+        } else if let Some((addr, prefix)) = host.split_once('/') {
+            let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+            let prefix: u8 = prefix.parse().map_err(|_| invalid())?;
+            if prefix > max_prefix(addr) {
+                return Err(invalid());
+            }
+            HostPattern::Cidr(addr, prefix)
+        } else if let Ok(addr) = host.parse::<IpAddr>() {
+            HostPattern::Cidr(addr, max_prefix(addr))
+        } else if !host.is_empty() {
+            HostPattern::Name(normalize_name(host))
+        } else {
+            return Err(invalid());
+        };
+
+        Ok(Rule { host, ports })
+    }
+
+    fn matches_port(&self, port: u16) -> bool {
+        self.ports.0 <= port && port <= self.ports.1
+    }
+
+    fn is_ip_based(&self) -> bool {
+        matches!(self.host, HostPattern::Any | HostPattern::Cidr(..))
+    }
+
+    // `name` as returned by normalize_name.
+    fn matches_name(&self, name: &str, port: u16) -> bool {
+        self.matches_port(port)
+            && match &self.host {
+                HostPattern::Any => true,
+                HostPattern::Cidr(..) => false,
+                HostPattern::Name(pattern) => wildcard_match(pattern, name),
+            }
+    }
+
+    fn matches_ip(&self, ip: IpAddr, port: u16) -> bool {
+        self.matches_port(port)
+            && match &self.host {
+                HostPattern::Any => true,
+                HostPattern::Cidr(net, prefix) => cidr_contains(*net, *prefix, ip),
+                HostPattern::Name(_) => false,
+            }
+    }
+}
+
+impl std::fmt::Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.host {
+            HostPattern::Any => write!(f, "*")?,
+            HostPattern::Cidr(addr, prefix) => write!(f, "{}/{}", addr, prefix)?,
+            HostPattern::Name(name) => write!(f, "{}", name)?,
+        }
+        match self.ports {
+            (0, u16::MAX) => Ok(()),
+            (low, high) if low == high => write!(f, " port {}", low),
+            (low, high) => write!(f, " ports {}-{}", low, high),
+        }
+    }
+}
+
+// Names are matched case-insensitively and without the trailing dot of a
+// fully qualified name, which resolves the same.
+fn normalize_name(host: &str) -> String {
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn describe(host: &str, ip: IpAddr) -> String {
+    if host.parse::<IpAddr>() == Ok(ip) {
+        host.to_string()
+    } else {
+        format!("{} ({})", host, ip)
+    }
+}
+
+fn max_prefix(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+fn cidr_contains(net: IpAddr, prefix: u8, ip: IpAddr) -> bool {
+    let ip = match (net, ip) {
+        (IpAddr::V4(_), IpAddr::V6(v6)) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => return false,
+        },
+        _ => ip,
+    };
+    match (net, ip) {
+        (IpAddr::V4(net), IpAddr::V4(ip)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(net) & mask == u32::from(ip) & mask
+        }
+        (IpAddr::V6(net), IpAddr::V6(ip)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(net) & mask == u128::from(ip) & mask
+        }
+        _ => false,
+    }
+}
+
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let Some((prefix, rest)) = pattern.split_once('*') else {
+        return pattern == name;
+    };
+    let Some(mut remaining) = name.strip_prefix(prefix) else {
+        return false;
+    };
+
+    let mut parts: Vec<&str> = rest.split('*').collect();
+    let suffix = parts.pop().unwrap_or("");
+    for part in parts {
+        match remaining.find(part) {
+            Some(pos) => remaining = &remaining[pos + part.len()..],
+            None => return false,
+        }
+    }
+    remaining.len() >= suffix.len() && remaining.ends_with(suffix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build(allow: &[&str], deny: &[&str]) -> Acl {
+        let config = AclConfig {
+            allow: allow.iter().map(|r| r.to_string()).collect(),
+            deny: deny.iter().map(|r| r.to_string()).collect(),
+        };
+        Acl::from_config(&config).unwrap()
+    }
+
+    #[test]
+    fn parses_rules() {
+        let cases = [
+            ("*", "*"),
+            ("*:22", "* port 22"),
+            ("10.0.0.0/8", "10.0.0.0/8"),
+            ("10.0.0.1:80-90", "10.0.0.1/32 ports 80-90"),
+            ("fd00::/8", "fd00::/8"),
+            ("[fd00::/8]:22", "fd00::/8 port 22"),
+            ("::1", "::1/128"),
+            ("[::1]:*", "::1/128"),
+            ("*.Example.COM:443", "*.example.com port 443"),
+            ("internal.corp.", "internal.corp"),
+        ];
+        for (rule, parsed) in cases {
+            assert_eq!(Rule::parse(rule).unwrap().to_string(), parsed, "{}", rule);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        let cases = [
+            "",
+            ":22",
+            "host:",
+            "host:x",
+            "host:90-80",
+            "host:1-2-3",
+            "host:70000",
+            "10.0.0.0/33",
+            "fd00::/129",
+            "10.0.0.0/x",
+            "bad/8",
+            "[fd00::/8",
+            "[fd00::/8]22",
+        ];
+        for rule in cases {
+            assert!(Rule::parse(rule).is_err(), "{}", rule);
+        }
+    }
+
+    #[test]
+    fn cidr_matching() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert!(cidr_contains(ip("10.0.0.0"), 8, ip("10.255.1.2")));
+        assert!(!cidr_contains(ip("10.0.0.0"), 8, ip("11.0.0.1")));
+        assert!(cidr_contains(ip("0.0.0.0"), 0, ip("192.0.2.1")));
+        assert!(cidr_contains(ip("192.0.2.1"), 32, ip("192.0.2.1")));
+        assert!(!cidr_contains(ip("192.0.2.1"), 32, ip("192.0.2.2")));
+        assert!(cidr_contains(ip("fd00::"), 8, ip("fdab::1")));
+        assert!(!cidr_contains(ip("fd00::"), 8, ip("fe80::1")));
+        assert!(cidr_contains(ip("::"), 0, ip("2001:db8::1")));
+        // IPv4-mapped IPv6 addresses match IPv4 ranges, and nothing else
+        // crosses families.
+        assert!(cidr_contains(ip("127.0.0.0"), 8, ip("::ffff:127.0.0.1")));
+        assert!(!cidr_contains(ip("127.0.0.0"), 8, ip("::1")));
+        assert!(!cidr_contains(ip("::"), 0, ip("127.0.0.1")));
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard_match("example.com", "example.com"));
+        assert!(!wildcard_match("example.com", "www.example.com"));
+        assert!(wildcard_match("*.example.com", "www.example.com"));
+        assert!(wildcard_match("*.example.com", "a.b.example.com"));
+        assert!(!wildcard_match("*.example.com", "example.com"));
+        assert!(!wildcard_match("*.example.com", "badexample.com"));
+        assert!(wildcard_match("db*.example.com", "db1.example.com"));
+        assert!(wildcard_match("a*b*c", "abc"));
+        assert!(wildcard_match("a*b*c", "axxbyyc"));
+        assert!(!wildcard_match("a*b*c", "acb"));
+        // The suffix can't reuse what a middle part matched.
+        assert!(!wildcard_match("*ab*ba", "aba"));
+        assert!(wildcard_match("*", "anything"));
+    }
+
+    #[test]
+    fn names_are_normalized() {
+        let acl = build(&[], &["*.internal.corp"]);
+        for host in ["db.internal.corp", "db.internal.corp.", "DB.Internal.Corp..."] {
+            assert!(acl.check_target(host, 22).is_err(), "{}", host);
+            let ip = "192.0.2.1".parse().unwrap();
+            assert!(acl.check_resolved(host, ip, 22).is_err(), "{}", host);
+        }
+
+        let acl = build(&["example.com:443"], &[]);
+        assert!(acl.check_target("Example.com.", 443).is_ok());
+        assert!(acl.check_target("example.com", 80).is_err());
+    }
+
+    #[test]
+    fn deny_wins_and_ip_rules_decide_after_resolution() {
+        let acl = build(&["10.0.0.0/8", "*.example.com"], &["10.0.0.1", "bad.example.com"]);
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert!(acl.check_target("bad.example.com", 80).is_err());
+        assert!(acl.check_target("10.0.0.1", 80).is_err());
+        assert!(acl.check_target("10.0.0.2", 80).is_ok());
+        // Could still resolve into 10/8.
+        assert!(acl.check_target("other.org", 80).is_ok());
+        assert!(acl.check_resolved("other.org", ip("10.1.1.1"), 80).is_ok());
+        assert!(acl.check_resolved("other.org", ip("192.0.2.1"), 80).is_err());
+        assert!(acl.check_resolved("www.example.com", ip("10.0.0.1"), 80).is_err());
+    }
+}
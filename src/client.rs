@@ -1,312 +1,1399 @@
-use std::collections::HashMap;
-use std::sync::atomic::{AtomicU32, Ordering};
-use std::sync::Arc;
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{mpsc, Mutex};
+use rand::Rng;
+use tokio::io::{AsyncReadExt, ReadHalf};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
 use tracing::{error, info, warn};
 
-use crate::crypto;
-use crate::protocol::{self, Frame, FrameType};
+use crate::config::{
+    self, ClientConfig, ForwardConfig, ForwardKind, ForwardProtocol, HttpProxyConfig,
+    ReconnectConfig, Socks5Config, SocksServerConfig,
+};
+use crate::crypto::FrameCipher;
+use crate::handshake::{self, Negotiated};
+use crate::heartbeat::{self, Heartbeat};
+use crate::http_proxy;
+use crate::protocol::{self, CloseReason, Frame, FrameSender, FrameType};
+use crate::quic;
+use crate::reload;
+use crate::shutdown::{self, Shutdown};
 use crate::socks5;
+use crate::socks_server::{self, Command, Reply, SocksRequest};
+use crate::tls::BoxedStream;
+use crate::transport::{self, Link, Transport};
+use crate::tunnel::{self, ConnIds, Connections, PendingConnection};
+use crate::udp::{self, Activity, LocalReturn};
+use crate::util;
 
-pub async fn run(config: &crate::config::ClientConfig) -> anyhow::Result<()> {
-    let key = crypto::derive_key(&config.token);
+// How often idle UDP flows are looked for.
+const UDP_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
 
-    let stream = if let Some(socks5_config) = &config.socks5 {
-        let (host, port) = parse_host_port(&config.server_addr)?;
-        info!(
-            "Connecting to server {} via SOCKS5 proxy {}",
-            config.server_addr, socks5_config.addr
-        );
-        socks5::connect(
-            &socks5_config.addr,
-            &host,
-            port,
-            socks5_config.username.as_deref(),
-            socks5_config.password.as_deref(),
-        )
-        .await?
-    } else {
-        TcpStream::connect(&config.server_addr).await?
-    };
+// How long a session has to stay up for the reconnect delay to start over.
+const STABLE_SESSION: Duration = Duration::from_secs(30);
 
-    stream.set_nodelay(true)?;
-    info!("Connected to server {}", config.server_addr);
+struct Session {
+    tx: FrameSender,
+    // Server-assigned forward id per local forward id (see `Listeners`), for
+    // the forwards the server accepted.
+    forward_ids: Mutex<HashMap<usize, u32>>,
+    // Local target for each reverse forward the server accepted, by forward id.
+    reverse_targets: Mutex<HashMap<u32, String>>,
+    // Forwards registered on the running session after a reload, in the
+    // order their RegisterForwardResults will come back.
+    pending_registrations: Mutex<VecDeque<usize>>,
+    connections: Arc<Connections>,
+    // Dynamically targeted connections waiting to hear whether the server
+    // could connect them; receives the close reason on failure.
+    pending_dials: Mutex<HashMap<u32, oneshot::Sender<Result<(), CloseReason>>>>,
+    // Where datagrams for each open UDP flow are delivered locally.
+    udp_flows: Mutex<HashMap<u32, LocalReturn>>,
+    connect_timeout: Duration,
+}
 
-    let (mut reader, writer) = tokio::io::split(stream);
-    let writer = Arc::new(Mutex::new(writer));
+impl Session {
+    // Picks an id for a new connection or UDP flow on this session.
+    fn next_conn_id(&self, ids: &ConnIds) -> u32 {
+        ids.next(|id| {
+            self.connections.contains(id) || self.udp_flows.lock().unwrap().contains_key(&id)
+        })
+    }
 
-    let auth_frame = Frame {
-        frame_type: FrameType::Auth,
-        conn_id: 0,
-        data: config.token.as_bytes().to_vec(),
-    };
-    {
-        let mut w = writer.lock().await;
-        protocol::write_frame(&mut *w, &key, &auth_frame).await?;
+    fn add_forward(&self, id: usize, forward: &ForwardConfig, forward_id: u32) {
+        self.forward_ids.lock().unwrap().insert(id, forward_id);
+        if forward.kind == ForwardKind::Remote {
+            let target = forward.local_addr.clone();
+            self.reverse_targets.lock().unwrap().insert(forward_id, target);
+        }
+        info!("Registered forward: {} (id={})", describe_forward(forward), forward_id);
     }
 
-    let auth_result = protocol::read_frame(&mut reader, &key).await?;
-    if !matches!(auth_result.frame_type, FrameType::AuthResult) {
-        return Err(anyhow::anyhow!("Expected AuthResult frame"));
+    // Asks the server for a forward added by a reload; the answer arrives in
+    // `run_session`.
+    fn register(&self, id: usize, forward: &ForwardConfig) {
+        self.pending_registrations.lock().unwrap().push_back(id);
+        let _ = self.tx.send_control(register_frame(forward));
     }
 
-    let result = String::from_utf8(auth_result.data)?;
-    if result != "ok" {
-        return Err(anyhow::anyhow!("Authentication failed: {}", result));
+    fn unregister(&self, id: usize) {
+        if let Some(forward_id) = self.forward_ids.lock().unwrap().remove(&id) {
+            self.unregister_id(forward_id);
+        }
     }
 
-    info!("Authenticated successfully");
+    fn unregister_id(&self, forward_id: u32) {
+        self.reverse_targets.lock().unwrap().remove(&forward_id);
+        let frame = Frame {
+            frame_type: FrameType::UnregisterForward,
+            conn_id: 0,
+            data: forward_id.to_be_bytes().to_vec(),
+        };
+        let _ = self.tx.send_control(frame);
+    }
+}
 
-    let (writer_tx, mut writer_rx) = mpsc::channel::<Vec<u8>>(4096);
+// What a reload can change while the client runs.
+struct Client {
+    path: String,
+    config: ClientConfig,
+    transport: Box<dyn Transport>,
+    listeners: Listeners,
+    reloads: mpsc::Receiver<reload::Request>,
+}
 
-    let writer_clone = writer.clone();
-    let writer_handle = tokio::spawn(async move {
-        while let Some(raw_frame) = writer_rx.recv().await {
-            let mut w = writer_clone.lock().await;
-            if let Err(e) = w.write_all(&raw_frame).await {
-                error!("Control write error: {}", e);
-                break;
+impl Client {
+    // Re-reads the config file. Listeners and forwards change right away, on
+    // the running session if there is one; the way to the server, the token
+    // and the other session settings apply from the next connect.
+    async fn reload(&mut self, session: Option<&Arc<Session>>) -> anyhow::Result<String> {
+        let config = config::load_client_config(&self.path)?;
+        check_forwards(&config)?;
+        self.transport = transport::client_transport(&config)?;
+        let result = self.listeners.update(&config, session).await;
+        self.config = config;
+        result.map_err(|e| anyhow::anyhow!("{} (the other changes were applied)", e))
+    }
+}
+
+pub async fn run(config: ClientConfig, path: &str) -> anyhow::Result<()> {
+    let (session_tx, session_rx) = watch::channel::<Option<Arc<Session>>>(None);
+    let conn_ids = Arc::new(ConnIds::client());
+
+    check_forwards(&config)?;
+    let mut listeners = Listeners::new(session_rx, conn_ids);
+    listeners.update(&config, None).await?;
+
+    let mut client = Client {
+        path: path.to_string(),
+        transport: transport::client_transport(&config)?,
+        reloads: reload::listen(config.admin_socket.as_deref())?,
+        config,
+        listeners,
+    };
+
+    // The shutdown starts by closing the local listeners; the session then
+    // drains the connections already open.
+    let (mut trigger, shutdown) = shutdown::listen()?;
+    tokio::spawn(async move {
+        trigger.signaled().await;
+        trigger.drained().await
+    });
+
+    let mut backoff = Backoff::new(&client.config.reconnect);
+    let mut force_closed = 0;
+
+    loop {
+        let forwards = client.listeners.forwards();
+        let connect = connect_session(&client.config, client.transport.as_ref(), forwards);
+        // Heartbeats only start with the session, so a server that goes
+        // silent while it is being set up is given up on here.
+        let setup_timeout = Duration::from_millis(client.config.handshake_timeout_ms);
+        let connected = tokio::select! {
+            connected = tokio::time::timeout(setup_timeout, connect) => {
+                connected.unwrap_or_else(|_| {
+                    Err(anyhow::anyhow!("Timed out after {:?}", setup_timeout))
+                })
             }
-            if let Err(e) = w.flush().await {
-                error!("Control flush error: {}", e);
-                break;
+            () = shutdown.started() => break,
+            // Reconnects with whatever the reload changed.
+            Some(request) = client.reloads.recv() => {
+                request.finish(client.reload(None).await);
+                continue;
+            }
+        };
+        match connected {
+            Ok((reader, session, writer_handle)) => {
+                let started = Instant::now();
+                session_tx.send_replace(Some(session.clone()));
+
+                run_session(reader, &session, &mut client, &session_tx, &shutdown).await;
+                backoff.session_ended(started.elapsed());
+
+                if shutdown.is_started() {
+                    force_closed = session.connections.live();
+                }
+                session_tx.send_replace(None);
+                writer_handle.abort();
+                session.connections.clear();
+                session.pending_dials.lock().unwrap().clear();
+                session.udp_flows.lock().unwrap().clear();
+                info!("Connection closes since startup: {}", tunnel::close_stats());
+            }
+            Err(e) => {
+                error!("Failed to establish session: {}", e);
             }
         }
-    });
+        if shutdown.is_started() {
+            break;
+        }
 
-    let mut forward_map: HashMap<u32, (String, u32)> = HashMap::new();
+        let delay = backoff.next_delay();
+        info!("Reconnecting to server in {:?}", delay);
+        tokio::select! {
+            () = tokio::time::sleep(delay) => {}
+            () = shutdown.started() => break,
+            Some(request) = client.reloads.recv() => request.finish(client.reload(None).await),
+        }
+    }
+
+    info!("Shutdown complete, {} connections force-closed", force_closed);
+    Ok(())
+}
 
+fn check_forwards(config: &ClientConfig) -> anyhow::Result<()> {
     for forward in &config.forwards {
-        let register_frame = Frame {
-            frame_type: FrameType::RegisterForward,
-            conn_id: 0,
-            data: forward.remote_addr.as_bytes().to_vec(),
-        };
-        {
-            let mut w = writer.lock().await;
-            protocol::write_frame(&mut *w, &key, &register_frame).await?;
+        if forward.kind == ForwardKind::Remote && forward.protocol == ForwardProtocol::Udp {
+            return Err(anyhow::anyhow!(
+                "UDP is only supported for local forwards ({})",
+                forward.remote_addr
+            ));
         }
+    }
+    Ok(())
+}
 
-        let result_frame = protocol::read_frame(&mut reader, &key).await?;
-        if !matches!(result_frame.frame_type, FrameType::RegisterForwardResult) {
-            return Err(anyhow::anyhow!("Expected RegisterForwardResult frame"));
+// The local side of the forwards and proxies in the config, each listener
+// with the task serving it. A reload binds the ones that were added and
+// closes the ones that were removed; the others, and all open connections,
+// are left alone.
+struct Listeners {
+    forwards: Vec<LocalForward>,
+    socks: Option<(SocksServerConfig, JoinHandle<()>)>,
+    http: Option<(HttpProxyConfig, JoinHandle<()>)>,
+    next_forward_id: usize,
+    session_rx: watch::Receiver<Option<Arc<Session>>>,
+    conn_ids: Arc<ConnIds>,
+}
+
+// A forward from the config. `id` identifies it to its listener's task and
+// the session; remote forwards have no local listener.
+struct LocalForward {
+    id: usize,
+    config: ForwardConfig,
+    task: Option<JoinHandle<()>>,
+}
+
+impl Listeners {
+    fn new(
+        session_rx: watch::Receiver<Option<Arc<Session>>>,
+        conn_ids: Arc<ConnIds>,
+    ) -> Self {
+        Listeners {
+            forwards: Vec::new(),
+            socks: None,
+            http: None,
+            next_forward_id: 0,
+            session_rx,
+            conn_ids,
+        }
+    }
+
+    fn forwards(&self) -> Vec<(usize, ForwardConfig)> {
+        self.forwards
+            .iter()
+            .map(|forward| (forward.id, forward.config.clone()))
+            .collect()
+    }
+
+    // Brings the listeners in line with `config`, registering and
+    // unregistering forwards on `session`. Listeners are closed before new
+    // ones are bound, so a changed one can keep its address. One that fails
+    // to bind is left out, for the next reload to try again, and reported
+    // once the rest is done.
+    async fn update(
+        &mut self,
+        config: &ClientConfig,
+        session: Option<&Arc<Session>>,
+    ) -> anyhow::Result<String> {
+        // Forwards and proxies; `added` is the forwards the session still has
+        // to hear about.
+        let mut added = Vec::new();
+        let mut proxies_added = 0;
+        let mut removed = 0;
+        let mut errors = Vec::new();
+
+        let mut wanted: Vec<&ForwardConfig> = config.forwards.iter().collect();
+        let mut kept = Vec::new();
+        for forward in self.forwards.drain(..) {
+            match wanted.iter().position(|&w| *w == forward.config) {
+                Some(index) => {
+                    wanted.remove(index);
+                    kept.push(forward);
+                }
+                None => {
+                    info!("Removing forward {}", describe_forward(&forward.config));
+                    close(forward.task).await;
+                    if let Some(session) = session {
+                        session.unregister(forward.id);
+                    }
+                    removed += 1;
+                }
+            }
         }
+        self.forwards = kept;
 
-        if result_frame.data.is_empty() {
-            return Err(anyhow::anyhow!("Invalid RegisterForwardResult"));
+        // A proxy whose settings changed is closed here and bound again below.
+        let socks_changed = self.socks.as_ref().map(|(c, _)| c) != config.socks_server.as_ref();
+        if let Some((socks_config, task)) = self.socks.take_if(|_| socks_changed) {
+            info!("Closing SOCKS proxy on {}", socks_config.listen_addr);
+            close(Some(task)).await;
+            removed += 1;
+        }
+        let http_changed = self.http.as_ref().map(|(c, _)| c) != config.http_proxy.as_ref();
+        if let Some((http_config, task)) = self.http.take_if(|_| http_changed) {
+            info!("Closing HTTP proxy on {}", http_config.listen_addr);
+            close(Some(task)).await;
+            removed += 1;
         }
 
-        let status = result_frame.data[0];
-        if status == 0x00 {
-            if result_frame.data.len() < 5 {
-                return Err(anyhow::anyhow!("Invalid RegisterForwardResult data"));
+        let udp_idle_timeout = Duration::from_millis(config.udp_idle_timeout_ms);
+        for forward in wanted {
+            let id = self.next_forward_id;
+            match self.bind_forward(id, forward, udp_idle_timeout).await {
+                Ok(task) => {
+                    self.next_forward_id += 1;
+                    self.forwards.push(LocalForward {
+                        id,
+                        config: forward.clone(),
+                        task,
+                    });
+                    added.push((id, forward));
+                }
+                Err(e) => errors.push(e.to_string()),
             }
-            let forward_id = u32::from_be_bytes([
-                result_frame.data[1],
-                result_frame.data[2],
-                result_frame.data[3],
-                result_frame.data[4],
-            ]);
-            forward_map.insert(forward_id, (forward.local_addr.clone(), 0));
-            info!(
-                "Registered forward: {} -> {} (id={})",
-                forward.local_addr, forward.remote_addr, forward_id
-            );
-        } else {
-            let error_msg = String::from_utf8_lossy(&result_frame.data[1..]);
-            return Err(anyhow::anyhow!(
-                "Failed to register forward {} -> {}: {}",
-                forward.local_addr,
-                forward.remote_addr,
-                error_msg
-            ));
+        }
+
+        if let (None, Some(socks_config)) = (&self.socks, &config.socks_server) {
+            match self.bind_socks(socks_config, udp_idle_timeout).await {
+                Ok(task) => {
+                    self.socks = Some((socks_config.clone(), task));
+                    proxies_added += 1;
+                }
+                Err(e) => errors.push(e.to_string()),
+            }
+        }
+        if let (None, Some(http_config)) = (&self.http, &config.http_proxy) {
+            match self.bind_http(http_config).await {
+                Ok(task) => {
+                    self.http = Some((http_config.clone(), task));
+                    proxies_added += 1;
+                }
+                Err(e) => errors.push(e.to_string()),
+            }
+        }
+
+        if let Some(session) = session {
+            for &(id, forward) in &added {
+                session.register(id, forward);
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(anyhow::anyhow!(errors.join("; ")));
+        }
+        Ok(format!("{} added, {} removed", added.len() + proxies_added, removed))
+    }
+
+    // Records the server's answer to a forward `register`ed on the running
+    // session.
+    fn registered(&self, session: &Session, frame: &Frame) {
+        let Some(id) = session.pending_registrations.lock().unwrap().pop_front() else {
+            warn!("Unexpected RegisterForwardResult");
+            return;
+        };
+        let result = match register_result(&frame.data) {
+            Ok(result) => result,
+            Err(e) => {
+                warn!("{}", e);
+                return;
+            }
+        };
+        let forward = self.forwards.iter().find(|forward| forward.id == id);
+        match (forward, result) {
+            (Some(forward), Ok(forward_id)) => session.add_forward(id, &forward.config, forward_id),
+            (Some(forward), Err(reason)) => {
+                let description = describe_forward(&forward.config);
+                error!("Failed to register forward {}: {}", description, reason);
+            }
+            // Removed by another reload in the meantime.
+            (None, Ok(forward_id)) => session.unregister_id(forward_id),
+            (None, Err(_)) => {}
         }
     }
 
-    let connections: Arc<Mutex<HashMap<u32, tokio::io::WriteHalf<TcpStream>>>> =
-        Arc::new(Mutex::new(HashMap::new()));
-    let next_conn_id: Arc<AtomicU32> = Arc::new(AtomicU32::new(1));
+    fn close_all(&mut self) {
+        let tasks = self.forwards.iter().filter_map(|forward| forward.task.as_ref());
+        let socks = self.socks.iter().map(|(_, task)| task);
+        let http = self.http.iter().map(|(_, task)| task);
+        for task in tasks.chain(socks).chain(http) {
+            task.abort();
+        }
+    }
 
-    let mut tasks = tokio::task::JoinSet::new();
+    async fn bind_forward(
+        &self,
+        id: usize,
+        forward: &ForwardConfig,
+        udp_idle_timeout: Duration,
+    ) -> anyhow::Result<Option<JoinHandle<()>>> {
+        if forward.kind == ForwardKind::Remote {
+            return Ok(None);
+        }
 
-    for (&forward_id, (local_addr, _)) in &forward_map {
-        let listener = match TcpListener::bind(local_addr).await {
+        if forward.protocol == ForwardProtocol::Udp {
+            let socket = UdpSocket::bind(&forward.local_addr).await.map_err(|e| {
+                anyhow::anyhow!(
+                    "Failed to bind UDP socket on {}: {}",
+                    forward.local_addr,
+                    e
+                )
+            })?;
+            info!(
+                "Listening on {}/udp for forward -> {}",
+                forward.local_addr, forward.remote_addr
+            );
+
+            return Ok(Some(tokio::spawn(udp_forward_loop(
+                socket,
+                id,
+                self.session_rx.clone(),
+                self.conn_ids.clone(),
+                udp_idle_timeout,
+            ))));
+        }
+
+        let listener = match TcpListener::bind(&forward.local_addr).await {
             Ok(l) => l,
             Err(e) => {
                 return Err(anyhow::anyhow!(
                     "Failed to bind listener on {}: {}",
-                    local_addr,
+                    forward.local_addr,
                     e
                 ));
             }
         };
-        info!("Listening on {} for forward {}", local_addr, forward_id);
-
-        let tx = writer_tx.clone();
-        let conns = connections.clone();
-        let nid = next_conn_id.clone();
-        let l_key = key;
-        let l_forward_id = forward_id;
-
-        tasks.spawn(async move {
-            loop {
-                match listener.accept().await {
-                    Ok((stream, addr)) => {
-                        info!(
-                            "New connection on forward {}: {}",
-                            l_forward_id, addr
-                        );
-
-                        let conn_id = nid.fetch_add(1, Ordering::Relaxed);
-
-                        let _ = stream.set_nodelay(true);
-                        let (read_half, write_half) = tokio::io::split(stream);
-                        {
-                            let mut c = conns.lock().await;
-                            c.insert(conn_id, write_half);
-                        }
-
-                        let mut data = vec![0x00];
-                        data.extend_from_slice(&l_forward_id.to_be_bytes());
-                        let frame = Frame {
-                            frame_type: FrameType::NewConnection,
-                            conn_id,
-                            data,
-                        };
-                        if protocol::send_frame(&tx, &l_key, &frame).is_err() {
-                            let mut c = conns.lock().await;
-                            c.remove(&conn_id);
-                            continue;
-                        }
-
-                        let r_tx = tx.clone();
-                        let r_conns = conns.clone();
-                        let r_key = l_key;
-
-                        tokio::spawn(async move {
-                            let mut reader = read_half;
-                            let mut buf = vec![0u8; 32768];
-                            loop {
-                                match reader.read(&mut buf).await {
-                                    Ok(0) => break,
-                                    Ok(n) => {
-                                        let frame = Frame {
-                                            frame_type: FrameType::Data,
-                                            conn_id,
-                                            data: buf[..n].to_vec(),
-                                        };
-                                        if protocol::send_frame(&r_tx, &r_key, &frame).is_err() {
-                                            break;
-                                        }
-                                    }
-                                    Err(_) => break,
-                                }
-                            }
-
-                            {
-                                let mut c = r_conns.lock().await;
-                                c.remove(&conn_id);
-                            }
-                            info!("Connection {} closed (local read ended)", conn_id);
-                            let close_frame = Frame {
-                                frame_type: FrameType::CloseConnection,
-                                conn_id,
-                                data: vec![],
-                            };
-                            let _ = protocol::send_frame(&r_tx, &r_key, &close_frame);
-                        });
-                    }
-                    Err(e) => {
-                        error!(
-                            "Accept error on forward {}: {}",
-                            l_forward_id, e
-                        );
-                        break;
-                    }
-                }
+        info!(
+            "Listening on {} for forward -> {}",
+            forward.local_addr, forward.remote_addr
+        );
+
+        Ok(Some(tokio::spawn(accept_loop(
+            listener,
+            id,
+            self.session_rx.clone(),
+            self.conn_ids.clone(),
+        ))))
+    }
+
+    async fn bind_socks(
+        &self,
+        socks_config: &SocksServerConfig,
+        udp_idle_timeout: Duration,
+    ) -> anyhow::Result<JoinHandle<()>> {
+        let listener = TcpListener::bind(&socks_config.listen_addr)
+            .await
+            .map_err(|e| {
+                anyhow::anyhow!(
+                    "Failed to bind SOCKS listener on {}: {}",
+                    socks_config.listen_addr,
+                    e
+                )
+            })?;
+        info!("SOCKS proxy listening on {}", socks_config.listen_addr);
+
+        let credentials = match (&socks_config.username, &socks_config.password) {
+            (Some(username), Some(password)) => Some((username.clone(), password.clone())),
+            _ => None,
+        };
+        Ok(tokio::spawn(socks_accept_loop(
+            listener,
+            Arc::new(credentials),
+            Arc::new(socks_config.bind_proxy.clone()),
+            self.session_rx.clone(),
+            self.conn_ids.clone(),
+            udp_idle_timeout,
+        )))
+    }
+
+    async fn bind_http(&self, http_config: &HttpProxyConfig) -> anyhow::Result<JoinHandle<()>> {
+        let listener = TcpListener::bind(&http_config.listen_addr)
+            .await
+            .map_err(|e| {
+                anyhow::anyhow!(
+                    "Failed to bind HTTP proxy listener on {}: {}",
+                    http_config.listen_addr,
+                    e
+                )
+            })?;
+        info!("HTTP proxy listening on {}", http_config.listen_addr);
+
+        Ok(tokio::spawn(http_accept_loop(
+            listener,
+            Arc::new(http_config.clone()),
+            self.session_rx.clone(),
+            self.conn_ids.clone(),
+        )))
+    }
+}
+
+// Stops a listener's task and waits for it, so its socket is closed.
+async fn close(task: Option<JoinHandle<()>>) {
+    if let Some(task) = task {
+        task.abort();
+        let _ = task.await;
+    }
+}
+
+fn describe_forward(forward: &ForwardConfig) -> String {
+    match forward.kind {
+        ForwardKind::Local => format!("{} -> {}", forward.local_addr, forward.remote_addr),
+        ForwardKind::Remote => format!("{} <- {}", forward.local_addr, forward.remote_addr),
+    }
+}
+
+fn register_frame(forward: &ForwardConfig) -> Frame {
+    let frame_type = match forward.kind {
+        ForwardKind::Local => FrameType::RegisterForward,
+        ForwardKind::Remote => FrameType::RegisterReverseForward,
+    };
+    Frame {
+        frame_type,
+        conn_id: 0,
+        data: forward.remote_addr.as_bytes().to_vec(),
+    }
+}
+
+// The forward id from a RegisterForwardResult, or the server's reason for
+// refusing the forward.
+fn register_result(data: &[u8]) -> anyhow::Result<Result<u32, String>> {
+    match data.split_first() {
+        Some((0x00, id)) if id.len() >= 4 => {
+            Ok(Ok(u32::from_be_bytes([id[0], id[1], id[2], id[3]])))
+        }
+        Some((0x00, _)) => Err(anyhow::anyhow!("Invalid RegisterForwardResult data")),
+        Some((_, reason)) => Ok(Err(String::from_utf8_lossy(reason).into_owned())),
+        None => Err(anyhow::anyhow!("Invalid RegisterForwardResult")),
+    }
+}
+
+// The receiving side of a session, consumed by `run_session`.
+struct SessionReader {
+    reader: ReadHalf<BoxedStream>,
+    cipher: FrameCipher,
+    // Connections the server opens on streams of their own (QUIC only).
+    incoming: mpsc::Receiver<(Frame, BoxedStream)>,
+    negotiated: Negotiated,
+}
+
+// Opens a session and registers `forwards` on it, by local forward id.
+async fn connect_session(
+    config: &ClientConfig,
+    transport: &dyn Transport,
+    forwards: Vec<(usize, ForwardConfig)>,
+) -> anyhow::Result<(SessionReader, Arc<Session>, JoinHandle<()>)> {
+    let Link { stream, quic } = transport.connect().await?;
+
+    let (mut reader, mut writer) = tokio::io::split(stream);
+
+    let (mut ciphers, negotiated) =
+        handshake::client_handshake(&mut reader, &mut writer, &config.token).await?;
+
+    info!(
+        "Authenticated successfully (protocol version {}, capabilities 0x{:x})",
+        negotiated.version, negotiated.capabilities
+    );
+
+    let (tx, rx) = protocol::frame_channel();
+    let incoming = quic::accept_streams(quic.clone());
+    let session = Arc::new(Session {
+        tx,
+        forward_ids: Mutex::new(HashMap::new()),
+        reverse_targets: Mutex::new(HashMap::new()),
+        pending_registrations: Mutex::new(VecDeque::new()),
+        connections: Arc::new(Connections::new(
+            quic,
+            negotiated.supports(protocol::CAP_HALF_CLOSE),
+        )),
+        pending_dials: Mutex::new(HashMap::new()),
+        udp_flows: Mutex::new(HashMap::new()),
+        connect_timeout: Duration::from_millis(config.connect_timeout_ms),
+    });
+
+    for (id, forward) in &forwards {
+        protocol::write_frame(&mut writer, &mut ciphers.send, &register_frame(forward)).await?;
+
+        // The server may already be pinging if registration is slow.
+        let result_frame = loop {
+            let frame = protocol::read_frame(&mut reader, &mut ciphers.recv).await?;
+            if !matches!(frame.frame_type, FrameType::Ping) {
+                break frame;
             }
-        });
+            let pong = Frame {
+                frame_type: FrameType::Pong,
+                conn_id: 0,
+                data: frame.data,
+            };
+            protocol::write_frame(&mut writer, &mut ciphers.send, &pong).await?;
+        };
+        if !matches!(result_frame.frame_type, FrameType::RegisterForwardResult) {
+            return Err(anyhow::anyhow!("Expected RegisterForwardResult frame"));
+        }
+
+        match register_result(&result_frame.data)? {
+            Ok(forward_id) => session.add_forward(*id, forward, forward_id),
+            Err(reason) => {
+                let description = describe_forward(forward);
+                error!("Failed to register forward {}: {}", description, reason);
+            }
+        }
     }
 
+    let writer_handle = tunnel::spawn_writer(writer, ciphers.send, rx);
+
+    let reader = SessionReader {
+        reader,
+        cipher: ciphers.recv,
+        incoming,
+        negotiated,
+    };
+    Ok((reader, session, writer_handle))
+}
+
+async fn run_session(
+    reader: SessionReader,
+    session: &Arc<Session>,
+    client: &mut Client,
+    session_tx: &watch::Sender<Option<Arc<Session>>>,
+    shutdown: &Shutdown,
+) {
+    let (mut frames, reader_handle) = tunnel::spawn_reader(reader.reader, reader.cipher);
+    let mut incoming = reader.incoming;
+    let mut heartbeat = Heartbeat::new(&client.config.heartbeat, &reader.negotiated);
+    // Set once the shutdown has started, for the connections still open.
+    let mut drain_deadline: Option<Instant> = None;
     loop {
-        let frame = match protocol::read_frame(&mut reader, &key).await {
-            Ok(f) => f,
-            Err(e) => {
-                let msg = e.to_string();
-                if msg.contains("unexpected eof")
-                    || msg.contains("EOF")
-                    || msg.contains("reset")
-                {
-                    info!("Disconnected from server");
-                } else {
-                    error!("Read frame error: {}", e);
+        let (frame, stream) = tokio::select! {
+            frame = frames.recv() => match frame {
+                Some(Ok(frame)) => (frame, None),
+                Some(Err(e)) => {
+                    let msg = e.to_string();
+                    if msg.contains("unexpected eof")
+                        || msg.contains("early eof")
+                        || msg.contains("EOF")
+                        || msg.contains("reset")
+                        || msg.contains("closed")
+                        || msg.contains("lost")
+                    {
+                        info!("Disconnected from server");
+                    } else {
+                        error!("Read frame error: {}", e);
+                    }
+                    break;
                 }
+                None => break,
+            },
+            Some((frame, stream)) = incoming.recv() => (frame, Some(stream)),
+            result = heartbeat.tick(&session.tx) => match result {
+                Ok(()) => continue,
+                Err(e) => {
+                    warn!("Server timed out: {}", e);
+                    break;
+                }
+            },
+            () = shutdown.started(), if drain_deadline.is_none() => {
+                client.listeners.close_all();
+                info!("Draining {} connections", session.connections.live());
+                let _ = session.tx.send_control(Frame::go_away());
+                let grace = Duration::from_millis(client.config.shutdown_grace_ms);
+                drain_deadline = Some(Instant::now() + grace);
+                continue;
+            }
+            () = session.connections.drained(), if drain_deadline.is_some() => break,
+            () = shutdown.grace_expired(drain_deadline) => {
+                let open = session.connections.live();
+                let why = if shutdown.is_forced() {
+                    "Shutdown forced"
+                } else {
+                    "Grace period over"
+                };
+                warn!("{}, force-closing {} connections", why, open);
                 break;
             }
+            Some(request) = client.reloads.recv(), if drain_deadline.is_none() => {
+                request.finish(client.reload(Some(session)).await);
+                continue;
+            }
         };
 
         match frame.frame_type {
-            FrameType::Data => {
+            FrameType::NewConnection => {
                 let conn_id = frame.conn_id;
-                let mut conns = connections.lock().await;
-                if let Some(write_half) = conns.get_mut(&conn_id) {
-                    if let Err(e) = write_half.write_all(&frame.data).await {
-                        warn!("Write to local connection {} error: {}", conn_id, e);
-                        conns.remove(&conn_id);
-                        drop(conns);
-                        info!("Connection {} closed (write error)", conn_id);
-                        let close_frame = Frame {
-                            frame_type: FrameType::CloseConnection,
-                            conn_id,
-                            data: vec![],
-                        };
-                        let _ = protocol::send_frame(&writer_tx, &key, &close_frame);
-                    }
+                if drain_deadline.is_some() {
+                    let reason = CloseReason::Shutdown;
+                    tunnel::send_close(&session.tx, conn_id, reason, "client is shutting down");
+                    continue;
+                }
+                let target = frame
+                    .data
+                    .get(1..5)
+                    .map(|id| u32::from_be_bytes([id[0], id[1], id[2], id[3]]))
+                    .and_then(|forward_id| {
+                        session.reverse_targets.lock().unwrap().get(&forward_id).cloned()
+                    });
+                let Some(target) = target else {
+                    warn!("Invalid NewConnection frame for connection {}", conn_id);
+                    let reason = CloseReason::ConnectFailed;
+                    tunnel::send_close(&session.tx, conn_id, reason, "unknown forward id");
+                    continue;
+                };
+
+                let Some(pending) = session.connections.accept(conn_id, &session.tx, stream)
+                else {
+                    continue;
+                };
+                tokio::spawn(dial_reverse(pending, conn_id, target, session.clone()));
+            }
+            FrameType::NewConnectionResult => {
+                let waiter = session.pending_dials.lock().unwrap().remove(&frame.conn_id);
+                if let Some(waiter) = waiter {
+                    let _ = waiter.send(Ok(()));
+                }
+            }
+            FrameType::UdpDatagram => {
+                if let Some(local) = session.udp_flows.lock().unwrap().get(&frame.conn_id) {
+                    local.deliver(&frame.data);
                 }
             }
+            FrameType::Data => {
+                session.connections.route_data(&session.tx, frame);
+            }
+            FrameType::WindowUpdate => {
+                session.connections.handle_window_update(&session.tx, frame);
+            }
+            FrameType::Fin => {
+                session.connections.handle_fin(frame.conn_id);
+            }
             FrameType::CloseConnection => {
-                info!("Connection {} closed by server", frame.conn_id);
-                let mut conns = connections.lock().await;
-                conns.remove(&frame.conn_id);
+                let (reason, message) = tunnel::received_close(&frame);
+                if reason == CloseReason::Normal {
+                    info!("Connection {} closed by server", frame.conn_id);
+                } else {
+                    warn!(
+                        "Connection {} closed by server: {}",
+                        frame.conn_id,
+                        reason.describe(&message)
+                    );
+                }
+                session.connections.remove(frame.conn_id);
+                session.udp_flows.lock().unwrap().remove(&frame.conn_id);
+                let waiter = session.pending_dials.lock().unwrap().remove(&frame.conn_id);
+                if let Some(waiter) = waiter {
+                    let _ = waiter.send(Err(reason));
+                }
+            }
+            FrameType::RegisterForwardResult => client.listeners.registered(session, &frame),
+            FrameType::GoAway => {
+                info!("Server is shutting down, not opening new connections");
+                session_tx.send_replace(None);
             }
+            FrameType::Ping => heartbeat::send_pong(&session.tx, frame),
+            FrameType::Pong => heartbeat.handle_pong(&frame),
             _ => {
                 warn!("Unexpected frame type: 0x{:02x}", frame.frame_type as u8);
             }
         }
     }
+    reader_handle.abort();
+}
 
-    drop(writer_tx);
-    writer_handle.abort();
-    tasks.abort_all();
+async fn accept_loop(
+    listener: TcpListener,
+    id: usize,
+    session_rx: watch::Receiver<Option<Arc<Session>>>,
+    conn_ids: Arc<ConnIds>,
+) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                util::accept_failed(&format!("forward {}", id), e).await;
+                continue;
+            }
+        };
 
-    Ok(())
+        let Some(session) = session_rx.borrow().clone() else {
+            warn!("Rejecting connection from {}: not connected to server", addr);
+            continue;
+        };
+        let Some(forward_id) = session.forward_ids.lock().unwrap().get(&id).copied() else {
+            warn!(
+                "Rejecting connection from {}: forward was refused by the server",
+                addr
+            );
+            continue;
+        };
+
+        info!("New connection on forward {}: {}", forward_id, addr);
+
+        let conn_id = session.next_conn_id(&conn_ids);
+
+        let _ = stream.set_nodelay(true);
+        let mut target = vec![0x00];
+        target.extend_from_slice(&forward_id.to_be_bytes());
+
+        tokio::spawn(async move {
+            let opened = session.connections.open(conn_id, target, &session.tx).await;
+            let Ok(pending) = opened else {
+                return;
+            };
+            let reason = pending.run(stream).await;
+            session.connections.finish(&session.tx, conn_id, reason).await;
+        });
+    }
+}
+
+async fn socks_accept_loop(
+    listener: TcpListener,
+    credentials: Arc<Option<(String, String)>>,
+    bind_proxy: Arc<Option<Socks5Config>>,
+    session_rx: watch::Receiver<Option<Arc<Session>>>,
+    conn_ids: Arc<ConnIds>,
+    udp_idle_timeout: Duration,
+) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                util::accept_failed("SOCKS listener", e).await;
+                continue;
+            }
+        };
+
+        tokio::spawn(handle_socks(
+            stream,
+            addr,
+            credentials.clone(),
+            bind_proxy.clone(),
+            session_rx.clone(),
+            conn_ids.clone(),
+            udp_idle_timeout,
+        ));
+    }
+}
+
+// Runs the SOCKS handshake, asks the server to connect to the requested
+// target and only reports success to the SOCKS client once it has.
+async fn handle_socks(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    credentials: Arc<Option<(String, String)>>,
+    bind_proxy: Arc<Option<Socks5Config>>,
+    session_rx: watch::Receiver<Option<Arc<Session>>>,
+    conn_ids: Arc<ConnIds>,
+    udp_idle_timeout: Duration,
+) {
+    let _ = stream.set_nodelay(true);
+    let credentials = credentials.as_ref().as_ref().map(|(u, p)| (u.as_str(), p.as_str()));
+    let request = match socks_server::accept(&mut stream, credentials).await {
+        Ok(request) => request,
+        Err(e) => {
+            warn!("SOCKS handshake with {} failed: {}", addr, e);
+            return;
+        }
+    };
+    if request.command == Command::UdpAssociate {
+        let association =
+            udp_associate(stream, addr, request, session_rx, conn_ids, udp_idle_timeout);
+        association.await;
+        return;
+    }
+    if request.command == Command::Bind {
+        socks_bind(stream, addr, request, bind_proxy.as_ref().as_ref()).await;
+        return;
+    }
+    let target = request.target();
+
+    let Some(session) = session_rx.borrow().clone() else {
+        warn!("Rejecting SOCKS request from {}: not connected to server", addr);
+        let _ = request.reply(&mut stream, Reply::GeneralFailure).await;
+        return;
+    };
+
+    let conn_id = session.next_conn_id(&conn_ids);
+    info!("SOCKS connection {} from {} -> {}", conn_id, addr, target);
+
+    let pending = match open_dynamic(&session, conn_id, &target).await {
+        Ok(pending) => pending,
+        Err(reason) => {
+            let reply = match reason {
+                CloseReason::AclDenied => Reply::NotAllowed,
+                CloseReason::ConnectRefused => Reply::ConnectionRefused,
+                CloseReason::DnsFailure => Reply::HostUnreachable,
+                CloseReason::Timeout => Reply::TtlExpired,
+                _ => Reply::GeneralFailure,
+            };
+            let _ = request.reply(&mut stream, reply).await;
+            return;
+        }
+    };
+
+    let reason = match request.reply(&mut stream, Reply::Succeeded).await {
+        Ok(()) => pending.run(stream).await,
+        Err(_) => CloseReason::Normal,
+    };
+    session.connections.finish(&session.tx, conn_id, reason).await;
+}
+
+// Passes a BIND request on to `bind_proxy`, answering the SOCKS client with
+// the address the proxy listens on and then with the peer that connected to
+// it, and relays between the two. This connection bypasses the tunnel.
+async fn socks_bind(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    request: SocksRequest,
+    bind_proxy: Option<&Socks5Config>,
+) {
+    let target = request.target();
+    let Some(proxy) = bind_proxy else {
+        warn!("Rejecting SOCKS BIND from {} for {}: no bind_proxy configured", addr, target);
+        let _ = request.reply(&mut stream, Reply::NotAllowed).await;
+        return;
+    };
+
+    let bind = match TcpStream::connect(&proxy.addr).await {
+        Ok(upstream) => socks5::bind(upstream, proxy, &request.host, request.port).await,
+        Err(e) => Err(anyhow::anyhow!("Failed to connect to {}: {}", proxy.addr, e)),
+    };
+    let bind = match bind {
+        Ok(bind) => bind,
+        Err(e) => {
+            warn!("SOCKS BIND from {} for {} failed: {}", addr, target, e);
+            let _ = request.reply(&mut stream, Reply::GeneralFailure).await;
+            return;
+        }
+    };
+    info!("SOCKS BIND from {} for {} listening on {}", addr, target, bind.bound);
+    if request.reply_address(&mut stream, &bind.bound).await.is_err() {
+        return;
+    }
+
+    let (mut upstream, peer) = match bind.accept().await {
+        Ok(accepted) => accepted,
+        Err(e) => {
+            warn!("SOCKS BIND from {} for {} failed: {}", addr, target, e);
+            let _ = request.reply(&mut stream, Reply::GeneralFailure).await;
+            return;
+        }
+    };
+    info!("SOCKS BIND from {} accepted {}", addr, peer);
+    if request.reply_address(&mut stream, &peer).await.is_err() {
+        return;
+    }
+    let _ = tokio::io::copy_bidirectional(&mut stream, &mut upstream).await;
+}
+
+// Relays datagrams for a SOCKS5 UDP ASSOCIATE request, with one flow per
+// target, for as long as the client keeps its TCP connection open.
+async fn udp_associate(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    request: SocksRequest,
+    session_rx: watch::Receiver<Option<Arc<Session>>>,
+    conn_ids: Arc<ConnIds>,
+    idle_timeout: Duration,
+) {
+    let bound = match stream.local_addr() {
+        Ok(local) => UdpSocket::bind((local.ip(), 0)).await,
+        Err(e) => Err(e),
+    };
+    let (socket, bound_addr) = match bound.and_then(|s| s.local_addr().map(|a| (s, a))) {
+        Ok(bound) => bound,
+        Err(e) => {
+            warn!("Failed to bind UDP relay socket for {}: {}", addr, e);
+            let _ = request.reply(&mut stream, Reply::GeneralFailure).await;
+            return;
+        }
+    };
+    if request.reply_bound(&mut stream, bound_addr).await.is_err() {
+        return;
+    }
+    info!("SOCKS UDP association from {} on {}", addr, bound_addr);
+
+    let socket = Arc::new(socket);
+    let mut flows = UdpFlows::new();
+    let mut sweep = tokio::time::interval(UDP_SWEEP_INTERVAL);
+    let mut buf = vec![0u8; udp::MAX_DATAGRAM_SIZE];
+    let mut control = [0u8; 64];
+    loop {
+        tokio::select! {
+            result = stream.read(&mut control) => match result {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            },
+            result = socket.recv_from(&mut buf) => {
+                let Ok((n, peer)) = result else {
+                    continue;
+                };
+                // Only the client that asked for the association may use it.
+                if peer.ip() != addr.ip() {
+                    continue;
+                }
+                let Some((host, port, offset)) = socks_server::parse_udp_header(&buf[..n]) else {
+                    continue;
+                };
+                let Some(session) = session_rx.borrow().clone() else {
+                    continue;
+                };
+
+                let target = config::format_host_port(&host, port);
+                let open = |flow_id| {
+                    info!("UDP flow {} from {} -> {}", flow_id, peer, target);
+                    let mut data = vec![0x01];
+                    data.extend_from_slice(target.as_bytes());
+                    let local = LocalReturn {
+                        socket: socket.clone(),
+                        peer,
+                        header: socks_server::udp_header(&host, port),
+                        activity: Activity::new(),
+                    };
+                    (data, local)
+                };
+                flows.send((peer, target.clone()), &session, &conn_ids, open, &buf[offset..n]);
+            }
+            _ = sweep.tick() => flows.expire(idle_timeout),
+        }
+    }
+
+    flows.close_all();
+    info!("SOCKS UDP association from {} ended", addr);
+}
+
+async fn http_accept_loop(
+    listener: TcpListener,
+    config: Arc<HttpProxyConfig>,
+    session_rx: watch::Receiver<Option<Arc<Session>>>,
+    conn_ids: Arc<ConnIds>,
+) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                util::accept_failed("HTTP proxy listener", e).await;
+                continue;
+            }
+        };
+
+        tokio::spawn(handle_http(
+            stream,
+            addr,
+            config.clone(),
+            session_rx.clone(),
+            conn_ids.clone(),
+        ));
+    }
+}
+
+async fn handle_http(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    config: Arc<HttpProxyConfig>,
+    session_rx: watch::Receiver<Option<Arc<Session>>>,
+    conn_ids: Arc<ConnIds>,
+) {
+    let _ = stream.set_nodelay(true);
+    let credentials = match (&config.username, &config.password) {
+        (Some(username), Some(password)) => Some((username.as_str(), password.as_str())),
+        _ => None,
+    };
+    let request = match http_proxy::accept(&mut stream, credentials, config.allow_plain_http).await
+    {
+        Ok(request) => request,
+        Err(e) => {
+            warn!("HTTP proxy request from {} failed: {}", addr, e);
+            return;
+        }
+    };
+
+    let Some(session) = session_rx.borrow().clone() else {
+        warn!("Rejecting HTTP proxy request from {}: not connected to server", addr);
+        let _ = request.reply(&mut stream, http_proxy::Reply::BadGateway).await;
+        return;
+    };
+
+    let conn_id = session.next_conn_id(&conn_ids);
+    info!("HTTP proxy connection {} from {} -> {}", conn_id, addr, request.target);
+
+    let pending = match open_dynamic(&session, conn_id, &request.target).await {
+        Ok(pending) => pending,
+        Err(reason) => {
+            let reply = match reason {
+                CloseReason::AclDenied => http_proxy::Reply::Forbidden,
+                CloseReason::Timeout => http_proxy::Reply::GatewayTimeout,
+                CloseReason::LimitExceeded | CloseReason::Shutdown => {
+                    http_proxy::Reply::ServiceUnavailable
+                }
+                _ => http_proxy::Reply::BadGateway,
+            };
+            let _ = request.reply(&mut stream, reply).await;
+            return;
+        }
+    };
+
+    let reason = match request.reply(&mut stream, http_proxy::Reply::Established).await {
+        Ok(()) => {
+            let stream = request.client_stream(stream);
+            pending.run_with_initial_data(stream, request.initial_data).await
+        }
+        Err(_) => CloseReason::Normal,
+    };
+    session.connections.finish(&session.tx, conn_id, reason).await;
+}
+
+// Asks the server to connect `conn_id` to a target chosen by a local proxy
+// client and waits until it has. On failure returns the close reason the
+// server sent.
+async fn open_dynamic(
+    session: &Session,
+    conn_id: u32,
+    target: &str,
+) -> Result<PendingConnection, CloseReason> {
+    let (result_tx, result_rx) = oneshot::channel();
+    session.pending_dials.lock().unwrap().insert(conn_id, result_tx);
+
+    let mut data = vec![0x01];
+    data.extend_from_slice(target.as_bytes());
+    let pending = match session.connections.open(conn_id, data, &session.tx).await {
+        Ok(pending) => pending,
+        Err(_) => {
+            session.pending_dials.lock().unwrap().remove(&conn_id);
+            return Err(CloseReason::ConnectFailed);
+        }
+    };
+
+    match result_rx.await {
+        Ok(Ok(())) => Ok(pending),
+        Ok(Err(status)) => Err(status),
+        Err(_) => Err(CloseReason::ConnectFailed),
+    }
 }
 
-fn parse_host_port(addr: &str) -> anyhow::Result<(String, u16)> {
-    let (host, port_str) = if addr.starts_with('[') {
-        let close_bracket = addr
-            .find(']')
-            .ok_or_else(|| anyhow::anyhow!("Invalid IPv6 address: {}", addr))?;
-        let host = addr[1..close_bracket].to_string();
-        let rest = &addr[close_bracket + 1..];
-        if rest.starts_with(':') {
-            (host, &rest[1..])
-        } else {
-            return Err(anyhow::anyhow!("Missing port in address: {}", addr));
-        }
-    } else {
-        let colon_pos = addr
-            .rfind(':')
-            .ok_or_else(|| anyhow::anyhow!("Missing port in address: {}", addr))?;
-        (addr[..colon_pos].to_string(), &addr[colon_pos + 1..])
+// Connects a reverse forward's connection to its local target, off the frame
+// loop so a slow dial does not hold up other connections.
+async fn dial_reverse(
+    pending: PendingConnection,
+    conn_id: u32,
+    target: String,
+    session: Arc<Session>,
+) {
+    let stream = match tokio::time::timeout(session.connect_timeout, TcpStream::connect(&target))
+        .await
+    {
+        Ok(Ok(s)) => s,
+        Ok(Err(e)) => {
+            warn!("Failed to connect to {}: {}", target, e);
+            session.connections.remove(conn_id);
+            let reason = tunnel::io_close_reason(&e);
+            tunnel::send_close(&session.tx, conn_id, reason, &e.to_string());
+            return;
+        }
+        Err(_) => {
+            warn!("Timed out connecting to {}", target);
+            session.connections.remove(conn_id);
+            let reason = CloseReason::Timeout;
+            tunnel::send_close(&session.tx, conn_id, reason, "connect timed out");
+            return;
+        }
     };
 
-    let port = port_str
-        .parse::<u16>()
-        .map_err(|_| anyhow::anyhow!("Invalid port: {}", port_str))?;
+    let _ = stream.set_nodelay(true);
+    info!("Connected to {} for connection {}", target, conn_id);
+
+    let reason = pending.run(stream).await;
+    session.connections.finish(&session.tx, conn_id, reason).await;
+}
+
+async fn udp_forward_loop(
+    socket: UdpSocket,
+    id: usize,
+    session_rx: watch::Receiver<Option<Arc<Session>>>,
+    conn_ids: Arc<ConnIds>,
+    idle_timeout: Duration,
+) {
+    let socket = Arc::new(socket);
+    let mut flows = UdpFlows::new();
+    let mut sweep = tokio::time::interval(UDP_SWEEP_INTERVAL);
+    let mut buf = vec![0u8; udp::MAX_DATAGRAM_SIZE];
+    loop {
+        tokio::select! {
+            result = socket.recv_from(&mut buf) => {
+                let (n, peer) = match result {
+                    Ok(received) => received,
+                    Err(e) => {
+                        warn!("UDP receive error on forward {}: {}", id, e);
+                        continue;
+                    }
+                };
+                // Without a session, or if the server refused the forward, the
+                // datagram is dropped.
+                let Some(session) = session_rx.borrow().clone() else {
+                    continue;
+                };
+                let Some(forward_id) = session.forward_ids.lock().unwrap().get(&id).copied() else {
+                    continue;
+                };
+
+                let open = |flow_id| {
+                    info!("New UDP flow {} on forward {}: {}", flow_id, forward_id, peer);
+                    let mut data = vec![0x00];
+                    data.extend_from_slice(&forward_id.to_be_bytes());
+                    let local = LocalReturn {
+                        socket: socket.clone(),
+                        peer,
+                        header: Vec::new(),
+                        activity: Activity::new(),
+                    };
+                    (data, local)
+                };
+                flows.send(peer, &session, &conn_ids, open, &buf[..n]);
+            }
+            _ = sweep.tick() => flows.expire(idle_timeout),
+        }
+    }
+}
+
+// The tunneled UDP flows of one local socket, keyed by whatever identifies a
+// flow locally: the peer address for a UDP forward, the peer and target for a
+// SOCKS UDP association.
+struct UdpFlows<K> {
+    flows: HashMap<K, LocalFlow>,
+}
+
+struct LocalFlow {
+    session: Arc<Session>,
+    flow_id: u32,
+    activity: Arc<Activity>,
+}
+
+impl<K: Hash + Eq> UdpFlows<K> {
+    fn new() -> Self {
+        UdpFlows {
+            flows: HashMap::new(),
+        }
+    }
+
+    // Sends a datagram on the flow for `key`, first opening one if there is
+    // none, or the old one was closed or belongs to an earlier session. `open`
+    // gives the NewUdpFlow payload and the flow's local end.
+    fn send(
+        &mut self,
+        key: K,
+        session: &Arc<Session>,
+        conn_ids: &ConnIds,
+        open: impl FnOnce(u32) -> (Vec<u8>, LocalReturn),
+        payload: &[u8],
+    ) {
+        let existing = self.flows.get(&key).filter(|flow| flow.is_open(session));
+        let flow_id = match existing {
+            Some(flow) => {
+                flow.activity.touch();
+                flow.flow_id
+            }
+            None => {
+                let flow_id = session.next_conn_id(conn_ids);
+                let (target, local) = open(flow_id);
+                let activity = local.activity.clone();
+                activity.touch();
+                session.udp_flows.lock().unwrap().insert(flow_id, local);
+
+                let frame = Frame {
+                    frame_type: FrameType::NewUdpFlow,
+                    conn_id: flow_id,
+                    data: target,
+                };
+                if session.tx.send_control(frame).is_err() {
+                    session.udp_flows.lock().unwrap().remove(&flow_id);
+                    return;
+                }
+
+                let flow = LocalFlow {
+                    session: session.clone(),
+                    flow_id,
+                    activity,
+                };
+                if let Some(old) = self.flows.insert(key, flow) {
+                    old.close();
+                }
+                flow_id
+            }
+        };
+
+        udp::send_datagram(&session.tx, flow_id, payload);
+    }
+
+    fn expire(&mut self, idle_timeout: Duration) {
+        self.flows.retain(|_, flow| {
+            let expired = flow.activity.idle_for() >= idle_timeout;
+            if expired {
+                info!("UDP flow {} expired", flow.flow_id);
+                flow.close();
+            }
+            !expired
+        });
+    }
+
+    fn close_all(&mut self) {
+        for (_, flow) in self.flows.drain() {
+            flow.close();
+        }
+    }
+}
+
+impl LocalFlow {
+    fn is_open(&self, session: &Arc<Session>) -> bool {
+        Arc::ptr_eq(&self.session, session)
+            && self.session.udp_flows.lock().unwrap().contains_key(&self.flow_id)
+    }
+
+    // Tells the server unless the flow is already gone from its session.
+    fn close(&self) {
+        if self.session.udp_flows.lock().unwrap().remove(&self.flow_id).is_none() {
+            return;
+        }
+        tunnel::send_close(&self.session.tx, self.flow_id, CloseReason::Normal, "");
+    }
+}
+
+struct Backoff {
+    initial: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    fn new(config: &ReconnectConfig) -> Self {
+        let initial = Duration::from_millis(config.initial_delay_ms.max(1));
+        let max = Duration::from_millis(config.max_delay_ms).max(initial);
+        Backoff {
+            initial,
+            max,
+            current: initial,
+        }
+    }
 
-    Ok((host, port))
+    // A session that stayed up for a while starts the delays over; one the
+    // server dropped soon after it was set up counts as a failed attempt, so
+    // a server stuck in a crash loop isn't hammered at the initial delay.
+    fn session_ended(&mut self, lasted: Duration) {
+        if lasted >= STABLE_SESSION {
+            self.current = self.initial;
+        }
+    }
+
+    fn next_delay(&mut self) -> Duration {
+        let ceiling = self.current;
+        self.current = (self.current * 2).min(self.max);
+        let ceiling_ms = ceiling.as_millis() as u64;
+        let jittered_ms = rand::thread_rng().gen_range(ceiling_ms / 2..=ceiling_ms);
+        Duration::from_millis(jittered_ms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backoff(initial_delay_ms: u64, max_delay_ms: u64) -> Backoff {
+        Backoff::new(&ReconnectConfig {
+            initial_delay_ms,
+            max_delay_ms,
+        })
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_max() {
+        let mut backoff = backoff(1000, 5000);
+        for ceiling_ms in [1000, 2000, 4000, 5000, 5000] {
+            let delay = backoff.next_delay().as_millis() as u64;
+            assert!((ceiling_ms / 2..=ceiling_ms).contains(&delay), "{}", delay);
+        }
+    }
+
+    #[test]
+    fn backoff_starts_over_only_after_a_stable_session() {
+        let mut backoff = backoff(1000, 60000);
+        backoff.next_delay();
+        backoff.next_delay();
+
+        backoff.session_ended(Duration::from_secs(1));
+        assert_eq!(backoff.current, Duration::from_millis(4000));
+
+        backoff.session_ended(STABLE_SESSION);
+        assert_eq!(backoff.current, Duration::from_millis(1000));
+    }
 }
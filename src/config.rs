@@ -1,9 +1,80 @@
 use serde::Deserialize;
 
+use crate::acl::AclConfig;
+
 #[derive(Debug, Deserialize)]
 pub struct ServerConfig {
-    pub token: String,
+    pub token: Option<String>,
     pub listen_addr: String,
+    #[serde(default = "default_connect_timeout_ms")]
+    pub connect_timeout_ms: u64,
+    #[serde(default = "default_handshake_timeout_ms")]
+    pub handshake_timeout_ms: u64,
+    #[serde(default)]
+    pub acl: AclConfig,
+    #[serde(default)]
+    pub users: Vec<UserConfig>,
+    #[serde(default)]
+    pub allow_reverse_forwards: bool,
+    #[serde(default)]
+    pub bind_acl: AclConfig,
+    #[serde(default = "default_udp_idle_timeout_ms")]
+    pub udp_idle_timeout_ms: u64,
+    pub tls: Option<ServerTlsConfig>,
+    pub websocket: Option<ServerWebSocketConfig>,
+    pub quic: Option<ServerQuicConfig>,
+    #[serde(default)]
+    pub heartbeat: HeartbeatConfig,
+    #[serde(default = "default_shutdown_grace_ms")]
+    pub shutdown_grace_ms: u64,
+    pub admin_socket: Option<String>,
+}
+
+// PEM files. With `client_ca_file` set, clients must also present a
+// certificate issued by that CA (mutual TLS).
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct ServerTlsConfig {
+    pub cert_file: String,
+    pub key_file: String,
+    pub client_ca_file: Option<String>,
+}
+
+// Accept clients as WebSocket upgrades of requests for `path`, e.g. behind an
+// HTTP reverse proxy. Other requests get the page in `decoy_file`, or a
+// minimal built-in one.
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct ServerWebSocketConfig {
+    #[serde(default = "default_websocket_path")]
+    pub path: String,
+    pub decoy_file: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UserConfig {
+    pub name: String,
+    pub token: String,
+    #[serde(flatten)]
+    pub acl: AclConfig,
+    #[serde(default)]
+    pub bind_acl: AclConfig,
+    pub max_forwards: Option<usize>,
+    pub max_connections: Option<usize>,
+}
+
+fn default_connect_timeout_ms() -> u64 {
+    10000
+}
+
+fn default_handshake_timeout_ms() -> u64 {
+    10000
+}
+
+fn default_udp_idle_timeout_ms() -> u64 {
+    60000
+}
+
+fn default_shutdown_grace_ms() -> u64 {
+    30000
 }
 
 #[derive(Debug, Deserialize)]
@@ -12,21 +83,256 @@ pub struct ClientConfig {
     pub server_addr: String,
     pub forwards: Vec<ForwardConfig>,
     pub socks5: Option<Socks5Config>,
+    pub upstream_http_proxy: Option<UpstreamHttpProxyConfig>,
+    #[serde(default)]
+    pub hops: Vec<HopConfig>,
+    pub socks_server: Option<SocksServerConfig>,
+    pub http_proxy: Option<HttpProxyConfig>,
+    pub tls: Option<ClientTlsConfig>,
+    pub websocket: Option<ClientWebSocketConfig>,
+    pub quic: Option<ClientQuicConfig>,
+    #[serde(default = "default_connect_timeout_ms")]
+    pub connect_timeout_ms: u64,
+    #[serde(default = "default_handshake_timeout_ms")]
+    pub handshake_timeout_ms: u64,
+    #[serde(default = "default_udp_idle_timeout_ms")]
+    pub udp_idle_timeout_ms: u64,
+    #[serde(default)]
+    pub reconnect: ReconnectConfig,
+    #[serde(default)]
+    pub heartbeat: HeartbeatConfig,
+    #[serde(default = "default_shutdown_grace_ms")]
+    pub shutdown_grace_ms: u64,
+    pub admin_socket: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+// A SOCKS proxy on the way to the server. SOCKS4 has no password, so only
+// `username` is sent (as the user id), and it can only connect to IPv4
+// addresses resolved on this side. With `resolve = "local"` SOCKS5 and
+// SOCKS4a proxies are given an address resolved here instead of the name.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct Socks5Config {
     pub addr: String,
     pub username: Option<String>,
     pub password: Option<String>,
+    #[serde(default)]
+    pub protocol: SocksProtocol,
+    #[serde(default)]
+    pub resolve: SocksResolve,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SocksProtocol {
+    #[default]
+    Socks5,
+    Socks4,
+    Socks4a,
+}
+
+impl SocksProtocol {
+    pub fn name(self) -> &'static str {
+        match self {
+            SocksProtocol::Socks5 => "SOCKS5",
+            SocksProtocol::Socks4 => "SOCKS4",
+            SocksProtocol::Socks4a => "SOCKS4a",
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SocksResolve {
+    #[default]
+    Remote,
+    Local,
+}
+
+// An HTTP proxy the connection to the server is tunneled through with
+// CONNECT, as an alternative to [socks5]. Credentials are sent with Basic
+// auth; `headers` ("Name: value") are added to the CONNECT request. Without
+// this section, HTTPS_PROXY/NO_PROXY from the environment are honored.
+#[derive(Debug, Clone, Deserialize)]
+pub struct UpstreamHttpProxyConfig {
+    pub addr: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    #[serde(default)]
+    pub headers: Vec<String>,
+}
+
+// One hop on the way to the server, reached through the hops before it; the
+// server is reached through the last one.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum HopConfig {
+    Socks5(Socks5Config),
+    Http(UpstreamHttpProxyConfig),
+    Kproxy(JumpHostConfig),
 }
 
+impl HopConfig {
+    pub fn addr(&self) -> &str {
+        match self {
+            HopConfig::Socks5(config) => &config.addr,
+            HopConfig::Http(config) => &config.addr,
+            HopConfig::Kproxy(config) => &config.addr,
+        }
+    }
+}
+
+// Another kproxy server used as a jump host: the next hop is reached through
+// a dynamically targeted connection on a session of its own, optionally over
+// TLS.
+#[derive(Debug, Clone, Deserialize)]
+pub struct JumpHostConfig {
+    pub addr: String,
+    pub token: String,
+    pub tls: Option<ClientTlsConfig>,
+}
+
+// TLS for the connection to the server. `ca_file` replaces the built-in web
+// PKI roots, e.g. to pin a private CA or a self-signed server certificate;
+// `server_name` overrides the name sent as SNI and checked against the
+// certificate. `cert_file` and `key_file` give a client certificate for
+// servers that require one.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ClientTlsConfig {
+    pub ca_file: Option<String>,
+    pub server_name: Option<String>,
+    pub cert_file: Option<String>,
+    pub key_file: Option<String>,
+}
+
+// Connect to the server with a WebSocket upgrade request for `path` (wss://
+// when [tls] is set). `host` overrides the Host header, which defaults to
+// server_addr.
 #[derive(Debug, Deserialize)]
+pub struct ClientWebSocketConfig {
+    #[serde(default = "default_websocket_path")]
+    pub path: String,
+    pub host: Option<String>,
+}
+
+fn default_websocket_path() -> String {
+    "/".to_string()
+}
+
+// Also accept clients over QUIC on `listen_addr` (UDP), using the [tls]
+// certificate.
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct ServerQuicConfig {
+    pub listen_addr: String,
+    #[serde(default = "default_quic_idle_timeout_ms")]
+    pub idle_timeout_ms: u64,
+}
+
+// Connect to server_addr over QUIC instead of TCP. The server certificate is
+// checked as configured in [tls], against the web PKI roots without it.
+#[derive(Debug, Deserialize)]
+pub struct ClientQuicConfig {
+    #[serde(default = "default_quic_idle_timeout_ms")]
+    pub idle_timeout_ms: u64,
+    #[serde(default = "default_quic_keep_alive_ms")]
+    pub keep_alive_interval_ms: u64,
+}
+
+fn default_quic_idle_timeout_ms() -> u64 {
+    30000
+}
+
+fn default_quic_keep_alive_ms() -> u64 {
+    10000
+}
+
+// Local SOCKS5/SOCKS4a listener whose CONNECT targets are dialed by the
+// server, like `ssh -D`. BIND requests are passed on to `bind_proxy`, an
+// upstream SOCKS proxy reached directly rather than through the tunnel, and
+// refused without one.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct SocksServerConfig {
+    pub listen_addr: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub bind_proxy: Option<Socks5Config>,
+}
+
+// Local HTTP proxy listener. CONNECT targets are dialed by the server like
+// SOCKS ones; absolute-URI plain HTTP requests are accepted too when
+// `allow_plain_http` is set.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct HttpProxyConfig {
+    pub listen_addr: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    #[serde(default)]
+    pub allow_plain_http: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct ReconnectConfig {
+    pub initial_delay_ms: u64,
+    pub max_delay_ms: u64,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        ReconnectConfig {
+            initial_delay_ms: 1000,
+            max_delay_ms: 60000,
+        }
+    }
+}
+
+// Pings on the control connection, answered by the peer with Pongs. A
+// session whose Ping goes `timeout_ms` without a Pong is torn down (and, on
+// the client, reconnected). `interval_ms = 0` turns pings off.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HeartbeatConfig {
+    pub interval_ms: u64,
+    pub timeout_ms: u64,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        HeartbeatConfig {
+            interval_ms: 15000,
+            timeout_ms: 45000,
+        }
+    }
+}
+
+// A local forward listens on `local_addr` and the server dials `remote_addr`
+// (like `ssh -L`). A remote forward is the reverse (like `ssh -R`): the server
+// listens on `remote_addr` and the client dials `local_addr`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct ForwardConfig {
+    #[serde(default)]
+    pub kind: ForwardKind,
+    #[serde(default)]
+    pub protocol: ForwardProtocol,
     pub local_addr: String,
     pub remote_addr: String,
 }
 
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ForwardKind {
+    #[default]
+    Local,
+    Remote,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ForwardProtocol {
+    #[default]
+    Tcp,
+    Udp,
+}
+
 pub fn load_server_config(path: &str) -> anyhow::Result<ServerConfig> {
     let content = std::fs::read_to_string(path)?;
     let config: ServerConfig = toml::from_str(&content)?;
@@ -38,3 +344,38 @@ pub fn load_client_config(path: &str) -> anyhow::Result<ClientConfig> {
     let config: ClientConfig = toml::from_str(&content)?;
     Ok(config)
 }
+
+// Formats a target as `host:port`, with IPv6 literals in brackets.
+pub fn format_host_port(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+pub fn parse_host_port(addr: &str) -> anyhow::Result<(String, u16)> {
+    let (host, port_str) = if addr.starts_with('[') {
+        let close_bracket = addr
+            .find(']')
+            .ok_or_else(|| anyhow::anyhow!("Invalid IPv6 address: {}", addr))?;
+        let host = addr[1..close_bracket].to_string();
+        let rest = &addr[close_bracket + 1..];
+        if let Some(port_str) = rest.strip_prefix(':') {
+            (host, port_str)
+        } else {
+            return Err(anyhow::anyhow!("Missing port in address: {}", addr));
+        }
+    } else {
+        let colon_pos = addr
+            .rfind(':')
+            .ok_or_else(|| anyhow::anyhow!("Missing port in address: {}", addr))?;
+        (addr[..colon_pos].to_string(), &addr[colon_pos + 1..])
+    };
+
+    let port = port_str
+        .parse::<u16>()
+        .map_err(|_| anyhow::anyhow!("Invalid port: {}", port_str))?;
+
+    Ok((host, port))
+}
@@ -1,9 +1,13 @@
-use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::aead::{Aead, KeyInit, Payload};
 use aes_gcm::{Aes256Gcm, Key, Nonce};
-use rand::RngCore;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
 use sha2::{Digest, Sha256};
+use x25519_dalek::{EphemeralSecret, PublicKey};
 
-pub fn derive_key(token: &str) -> [u8; 32] {
+type HmacSha256 = Hmac<Sha256>;
+
+pub fn derive_psk(token: &str) -> [u8; 32] {
     let mut hasher = Sha256::new();
     hasher.update(token.as_bytes());
     let result = hasher.finalize();
@@ -12,38 +16,188 @@ pub fn derive_key(token: &str) -> [u8; 32] {
     key
 }
 
-pub fn encrypt(key: &[u8; 32], plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
-    let cipher_key = Key::<Aes256Gcm>::from_slice(key);
-    let cipher = Aes256Gcm::new(cipher_key);
+pub fn generate_ephemeral() -> (EphemeralSecret, [u8; 32]) {
+    let secret = EphemeralSecret::random_from_rng(rand::rngs::OsRng);
+    let public = PublicKey::from(&secret).to_bytes();
+    (secret, public)
+}
 
-    let mut nonce_bytes = [0u8; 12];
-    rand::rngs::OsRng.fill_bytes(&mut nonce_bytes);
-    let nonce = Nonce::from_slice(&nonce_bytes);
+pub fn transcript_hash(client_public: &[u8; 32], server_public: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"kproxy handshake v1");
+    hasher.update(client_public);
+    hasher.update(server_public);
+    hasher.finalize().into()
+}
+
+// Returns (client-to-server, server-to-client) keys. Both come from the
+// ephemeral exchange alone, so recording a session and later learning the
+// token does not recover them.
+pub fn derive_session_keys(
+    secret: EphemeralSecret,
+    peer_public: &[u8; 32],
+    transcript: &[u8; 32],
+) -> anyhow::Result<([u8; 32], [u8; 32])> {
+    let shared = secret.diffie_hellman(&PublicKey::from(*peer_public));
+    if !shared.was_contributory() {
+        return Err(anyhow::anyhow!("Invalid peer public key"));
+    }
+
+    let hkdf = Hkdf::<Sha256>::new(Some(transcript), shared.as_bytes());
+    let mut client_to_server = [0u8; 32];
+    let mut server_to_client = [0u8; 32];
+    hkdf.expand(b"kproxy client to server", &mut client_to_server)
+        .map_err(|e| anyhow::anyhow!("Key derivation failed: {}", e))?;
+    hkdf.expand(b"kproxy server to client", &mut server_to_client)
+        .map_err(|e| anyhow::anyhow!("Key derivation failed: {}", e))?;
+    Ok((client_to_server, server_to_client))
+}
+
+fn transcript_mac(psk: &[u8; 32], label: &[u8], transcript: &[u8; 32]) -> HmacSha256 {
+    let mut mac =
+        <HmacSha256 as Mac>::new_from_slice(psk).expect("HMAC accepts any key length");
+    mac.update(label);
+    mac.update(transcript);
+    mac
+}
+
+pub fn auth_mac(psk: &[u8; 32], label: &[u8], transcript: &[u8; 32]) -> [u8; 32] {
+    transcript_mac(psk, label, transcript).finalize().into_bytes().into()
+}
+
+pub fn verify_auth_mac(psk: &[u8; 32], label: &[u8], transcript: &[u8; 32], tag: &[u8]) -> bool {
+    transcript_mac(psk, label, transcript).verify_slice(tag).is_ok()
+}
+
+// One direction of an established session. Every frame carries an explicit
+// 64-bit sequence number that doubles as the AES-GCM nonce, and the receiver
+// only accepts the next number in order, so recorded frames cannot be replayed
+// or reordered. The session id (the handshake transcript hash) is bound in as
+// associated data, so a frame from one session never opens in another.
+pub struct FrameCipher {
+    cipher: Aes256Gcm,
+    session_id: [u8; 32],
+    seq: u64,
+}
+
+pub struct SessionCiphers {
+    pub send: FrameCipher,
+    pub recv: FrameCipher,
+}
 
-    let ciphertext = cipher
-        .encrypt(nonce, plaintext)
-        .map_err(|e| anyhow::anyhow!("Encryption failed: {}", e))?;
+impl FrameCipher {
+    pub fn new(key: &[u8; 32], session_id: [u8; 32]) -> Self {
+        let cipher_key = Key::<Aes256Gcm>::from_slice(key);
+        FrameCipher {
+            cipher: Aes256Gcm::new(cipher_key),
+            session_id,
+            seq: 0,
+        }
+    }
+
+    pub fn seal(&mut self, plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let seq = self.seq;
+        self.seq = seq
+            .checked_add(1)
+            .ok_or_else(|| anyhow::anyhow!("Frame sequence number exhausted"))?;
+
+        let payload = Payload {
+            msg: plaintext,
+            aad: &self.session_id,
+        };
+        let ciphertext = self
+            .cipher
+            .encrypt(&seq_nonce(seq), payload)
+            .map_err(|e| anyhow::anyhow!("Encryption failed: {}", e))?;
+
+        let mut result = Vec::with_capacity(8 + ciphertext.len());
+        result.extend_from_slice(&seq.to_be_bytes());
+        result.extend_from_slice(&ciphertext);
+        Ok(result)
+    }
 
-    let mut result = Vec::with_capacity(12 + ciphertext.len());
-    result.extend_from_slice(&nonce_bytes);
-    result.extend_from_slice(&ciphertext);
-    Ok(result)
+    pub fn open(&mut self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        if data.len() < 8 + 16 {
+            return Err(anyhow::anyhow!("Data too short for decryption"));
+        }
+
+        let seq = u64::from_be_bytes(data[..8].try_into().unwrap());
+        if seq != self.seq {
+            return Err(anyhow::anyhow!(
+                "Out of sequence frame: expected {}, got {}",
+                self.seq,
+                seq
+            ));
+        }
+
+        let payload = Payload {
+            msg: &data[8..],
+            aad: &self.session_id,
+        };
+        let plaintext = self
+            .cipher
+            .decrypt(&seq_nonce(seq), payload)
+            .map_err(|e| anyhow::anyhow!("Decryption failed: {}", e))?;
+
+        self.seq += 1;
+        Ok(plaintext)
+    }
 }
 
-pub fn decrypt(key: &[u8; 32], data: &[u8]) -> anyhow::Result<Vec<u8>> {
-    if data.len() < 12 + 16 {
-        return Err(anyhow::anyhow!("Data too short for decryption"));
+fn seq_nonce(seq: u64) -> Nonce<aes_gcm::aead::consts::U12> {
+    let mut nonce_bytes = [0u8; 12];
+    nonce_bytes[4..].copy_from_slice(&seq.to_be_bytes());
+    *Nonce::from_slice(&nonce_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 32] = [7; 32];
+    const SESSION_ID: [u8; 32] = [1; 32];
+
+    #[test]
+    fn frames_open_in_order() {
+        let mut send = FrameCipher::new(&KEY, SESSION_ID);
+        let mut recv = FrameCipher::new(&KEY, SESSION_ID);
+        for message in [&b"one"[..], b"two", b""] {
+            let sealed = send.seal(message).unwrap();
+            assert_eq!(recv.open(&sealed).unwrap(), message);
+        }
     }
 
-    let nonce = Nonce::from_slice(&data[..12]);
-    let ciphertext = &data[12..];
+    #[test]
+    fn replayed_frame_is_rejected() {
+        let mut send = FrameCipher::new(&KEY, SESSION_ID);
+        let mut recv = FrameCipher::new(&KEY, SESSION_ID);
+        let sealed = send.seal(b"once").unwrap();
+        recv.open(&sealed).unwrap();
 
-    let cipher_key = Key::<Aes256Gcm>::from_slice(key);
-    let cipher = Aes256Gcm::new(cipher_key);
+        let err = recv.open(&sealed).unwrap_err();
+        assert_eq!(err.to_string(), "Out of sequence frame: expected 1, got 0");
+    }
 
-    let plaintext = cipher
-        .decrypt(nonce, ciphertext)
-        .map_err(|e| anyhow::anyhow!("Decryption failed: {}", e))?;
+    #[test]
+    fn reordered_frame_is_rejected() {
+        let mut send = FrameCipher::new(&KEY, SESSION_ID);
+        let mut recv = FrameCipher::new(&KEY, SESSION_ID);
+        let first = send.seal(b"first").unwrap();
+        let second = send.seal(b"second").unwrap();
 
-    Ok(plaintext)
+        let err = recv.open(&second).unwrap_err();
+        assert_eq!(err.to_string(), "Out of sequence frame: expected 0, got 1");
+        // The rejected frame doesn't move the receiver on.
+        assert_eq!(recv.open(&first).unwrap(), b"first");
+    }
+
+    #[test]
+    fn frame_from_another_session_is_rejected() {
+        let mut send = FrameCipher::new(&KEY, [2; 32]);
+        let mut recv = FrameCipher::new(&KEY, SESSION_ID);
+        let sealed = send.seal(b"elsewhere").unwrap();
+
+        let err = recv.open(&sealed).unwrap_err();
+        assert!(err.to_string().starts_with("Decryption failed"), "{}", err);
+    }
 }
@@ -0,0 +1,344 @@
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::crypto::{self, FrameCipher, SessionCiphers};
+use crate::protocol::{self, Frame, FrameType, CAPABILITIES, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+
+// The handshake is an ephemeral X25519 exchange in the clear, after which both
+// sides switch to the derived per-direction keys and prove knowledge of the
+// token with an HMAC over the transcript: first the client in an Auth frame,
+// then the server in AuthResult. The token itself never crosses the wire.
+// Each side also sends its protocol version (u16) and capability bits (u32)
+// along with its MAC.
+//
+//   client -> server  Auth           client public key                (plain)
+//   server -> client  AuthChallenge  server public key                (plain)
+//   client -> server  Auth           client MAC + version + caps      (encrypted)
+//   server -> client  AuthResult     0x00 + server MAC + version + caps
+//                                    0x01 + error message             (encrypted)
+const CLIENT_MAC_LABEL: &[u8] = b"kproxy client auth";
+const SERVER_MAC_LABEL: &[u8] = b"kproxy server auth";
+
+const MAC_LEN: usize = 32;
+
+// What both sides of a session support.
+#[derive(Debug, Clone, Copy)]
+pub struct Negotiated {
+    pub version: u16,
+    pub capabilities: u32,
+}
+
+impl Negotiated {
+    pub fn supports(&self, capability: u32) -> bool {
+        self.capabilities & capability != 0
+    }
+}
+
+fn encode_version() -> Vec<u8> {
+    let mut data = PROTOCOL_VERSION.to_be_bytes().to_vec();
+    data.extend_from_slice(&CAPABILITIES.to_be_bytes());
+    data
+}
+
+// Agrees on the version and capabilities with the peer's, as sent after its
+// MAC. A peer from before versioning sent nothing and counts as version 0.
+fn negotiate(peer: &[u8]) -> Result<Negotiated, String> {
+    let version = peer
+        .get(..2)
+        .map_or(0, |v| u16::from_be_bytes([v[0], v[1]]));
+    let capabilities = peer
+        .get(2..6)
+        .map_or(0, |c| u32::from_be_bytes([c[0], c[1], c[2], c[3]]));
+    if version < MIN_PROTOCOL_VERSION {
+        return Err(format!(
+            "unsupported protocol version {} (need at least {})",
+            version, MIN_PROTOCOL_VERSION
+        ));
+    }
+    Ok(Negotiated {
+        version: version.min(PROTOCOL_VERSION),
+        capabilities: capabilities & CAPABILITIES,
+    })
+}
+
+pub async fn client_handshake<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    token: &str,
+) -> anyhow::Result<(SessionCiphers, Negotiated)>
+where
+    R: AsyncReadExt + Unpin,
+    W: AsyncWriteExt + Unpin,
+{
+    let psk = crypto::derive_psk(token);
+    let (secret, client_public) = crypto::generate_ephemeral();
+
+    let hello = Frame {
+        frame_type: FrameType::Auth,
+        conn_id: 0,
+        data: client_public.to_vec(),
+    };
+    protocol::write_plain_frame(writer, &hello).await?;
+
+    let challenge = protocol::read_plain_frame(reader).await?;
+    if !matches!(challenge.frame_type, FrameType::AuthChallenge) {
+        return Err(anyhow::anyhow!("Expected AuthChallenge frame"));
+    }
+    let server_public: [u8; 32] = challenge
+        .data
+        .as_slice()
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Invalid AuthChallenge frame"))?;
+
+    let transcript = crypto::transcript_hash(&client_public, &server_public);
+    let (client_to_server, server_to_client) =
+        crypto::derive_session_keys(secret, &server_public, &transcript)?;
+    let mut ciphers = SessionCiphers {
+        send: FrameCipher::new(&client_to_server, transcript),
+        recv: FrameCipher::new(&server_to_client, transcript),
+    };
+
+    let mut data = crypto::auth_mac(&psk, CLIENT_MAC_LABEL, &transcript).to_vec();
+    data.extend_from_slice(&encode_version());
+    let proof = Frame {
+        frame_type: FrameType::Auth,
+        conn_id: 0,
+        data,
+    };
+    protocol::write_frame(writer, &mut ciphers.send, &proof).await?;
+
+    let auth_result = protocol::read_frame(reader, &mut ciphers.recv).await?;
+    if !matches!(auth_result.frame_type, FrameType::AuthResult) {
+        return Err(anyhow::anyhow!("Expected AuthResult frame"));
+    }
+
+    let (mac, peer_version) = match auth_result.data.split_first() {
+        Some((0x00, rest)) => rest.split_at(MAC_LEN.min(rest.len())),
+        Some((_, reason)) => {
+            let reason = String::from_utf8_lossy(reason);
+            return Err(anyhow::anyhow!("Authentication failed: {}", reason));
+        }
+        None => return Err(anyhow::anyhow!("Invalid AuthResult frame")),
+    };
+    if !crypto::verify_auth_mac(&psk, SERVER_MAC_LABEL, &transcript, mac) {
+        return Err(anyhow::anyhow!("Server failed to prove knowledge of the token"));
+    }
+    let negotiated =
+        negotiate(peer_version).map_err(|e| anyhow::anyhow!("Server has {}", e))?;
+
+    Ok((ciphers, negotiated))
+}
+
+// Returns the index of the key in `psks` the client proved knowledge of.
+pub async fn server_handshake<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    psks: &[[u8; 32]],
+) -> anyhow::Result<(SessionCiphers, usize, Negotiated)>
+where
+    R: AsyncReadExt + Unpin,
+    W: AsyncWriteExt + Unpin,
+{
+    let hello = protocol::read_plain_frame(reader).await?;
+    if !matches!(hello.frame_type, FrameType::Auth) {
+        return Err(anyhow::anyhow!("Expected Auth frame"));
+    }
+    let client_public: [u8; 32] = hello
+        .data
+        .as_slice()
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("Invalid Auth frame"))?;
+
+    let (secret, server_public) = crypto::generate_ephemeral();
+    let challenge = Frame {
+        frame_type: FrameType::AuthChallenge,
+        conn_id: 0,
+        data: server_public.to_vec(),
+    };
+    protocol::write_plain_frame(writer, &challenge).await?;
+
+    let transcript = crypto::transcript_hash(&client_public, &server_public);
+    let (client_to_server, server_to_client) =
+        crypto::derive_session_keys(secret, &client_public, &transcript)?;
+    let mut ciphers = SessionCiphers {
+        send: FrameCipher::new(&server_to_client, transcript),
+        recv: FrameCipher::new(&client_to_server, transcript),
+    };
+
+    let proof = protocol::read_frame(reader, &mut ciphers.recv).await?;
+    let (mac, peer_version) = proof.data.split_at(MAC_LEN.min(proof.data.len()));
+    let matched = match proof.frame_type {
+        FrameType::Auth => psks
+            .iter()
+            .position(|psk| crypto::verify_auth_mac(psk, CLIENT_MAC_LABEL, &transcript, mac)),
+        _ => None,
+    };
+    let Some(index) = matched else {
+        send_auth_failure(writer, &mut ciphers.send, "auth failed").await?;
+        return Err(anyhow::anyhow!("Authentication failed"));
+    };
+    let negotiated = match negotiate(peer_version) {
+        Ok(negotiated) => negotiated,
+        Err(e) => {
+            send_auth_failure(writer, &mut ciphers.send, &e).await?;
+            return Err(anyhow::anyhow!("Client has {}", e));
+        }
+    };
+
+    let mut data = vec![0x00];
+    data.extend_from_slice(&crypto::auth_mac(&psks[index], SERVER_MAC_LABEL, &transcript));
+    data.extend_from_slice(&encode_version());
+    let response = Frame {
+        frame_type: FrameType::AuthResult,
+        conn_id: 0,
+        data,
+    };
+    protocol::write_frame(writer, &mut ciphers.send, &response).await?;
+
+    Ok((ciphers, index, negotiated))
+}
+
+async fn send_auth_failure<W>(
+    writer: &mut W,
+    cipher: &mut FrameCipher,
+    reason: &str,
+) -> anyhow::Result<()>
+where
+    W: AsyncWriteExt + Unpin,
+{
+    let mut data = vec![0x01];
+    data.extend_from_slice(reason.as_bytes());
+    let response = Frame {
+        frame_type: FrameType::AuthResult,
+        conn_id: 0,
+        data,
+    };
+    protocol::write_frame(writer, cipher, &response).await
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, split, DuplexStream, ReadHalf, WriteHalf};
+
+    use super::*;
+
+    type Halves = (ReadHalf<DuplexStream>, WriteHalf<DuplexStream>);
+
+    fn pipe() -> (Halves, Halves) {
+        let (client, server) = duplex(4096);
+        (split(client), split(server))
+    }
+
+    #[tokio::test]
+    async fn handshake_with_a_known_token() {
+        let ((mut cr, mut cw), (mut sr, mut sw)) = pipe();
+        let psks = [crypto::derive_psk("alice"), crypto::derive_psk("bob")];
+
+        let (client, server) = tokio::join!(
+            client_handshake(&mut cr, &mut cw, "bob"),
+            server_handshake(&mut sr, &mut sw, &psks),
+        );
+        let (mut client_ciphers, client_negotiated) = client.unwrap();
+        let (mut server_ciphers, index, server_negotiated) = server.unwrap();
+        assert_eq!(index, 1);
+        assert_eq!(client_negotiated.version, server_negotiated.version);
+        assert_eq!(client_negotiated.capabilities, CAPABILITIES);
+
+        // Both sides ended up with the same keys.
+        let sealed = client_ciphers.send.seal(b"ping").unwrap();
+        assert_eq!(server_ciphers.recv.open(&sealed).unwrap(), b"ping");
+        let sealed = server_ciphers.send.seal(b"pong").unwrap();
+        assert_eq!(client_ciphers.recv.open(&sealed).unwrap(), b"pong");
+    }
+
+    #[tokio::test]
+    async fn wrong_token_fails_both_sides() {
+        let ((mut cr, mut cw), (mut sr, mut sw)) = pipe();
+        let psks = [crypto::derive_psk("alice")];
+
+        let (client, server) = tokio::join!(
+            client_handshake(&mut cr, &mut cw, "mallory"),
+            server_handshake(&mut sr, &mut sw, &psks),
+        );
+        let err = client.err().unwrap();
+        assert_eq!(err.to_string(), "Authentication failed: auth failed");
+        let err = server.err().unwrap();
+        assert_eq!(err.to_string(), "Authentication failed");
+    }
+
+    #[tokio::test]
+    async fn tampered_client_proof_is_rejected() {
+        let ((mut cr, mut cw), (mut sr, mut sw)) = pipe();
+        let psk = crypto::derive_psk("alice");
+
+        let client = async {
+            let (secret, client_public) = crypto::generate_ephemeral();
+            let hello = Frame {
+                frame_type: FrameType::Auth,
+                conn_id: 0,
+                data: client_public.to_vec(),
+            };
+            protocol::write_plain_frame(&mut cw, &hello).await.unwrap();
+            let challenge = protocol::read_plain_frame(&mut cr).await.unwrap();
+            let server_public: [u8; 32] = challenge.data.as_slice().try_into().unwrap();
+
+            let transcript = crypto::transcript_hash(&client_public, &server_public);
+            let (client_to_server, _) =
+                crypto::derive_session_keys(secret, &server_public, &transcript).unwrap();
+            let mut send = FrameCipher::new(&client_to_server, transcript);
+            let mut data = crypto::auth_mac(&psk, CLIENT_MAC_LABEL, &transcript).to_vec();
+            data[0] ^= 0x01;
+            data.extend_from_slice(&encode_version());
+            let proof = Frame {
+                frame_type: FrameType::Auth,
+                conn_id: 0,
+                data,
+            };
+            protocol::write_frame(&mut cw, &mut send, &proof).await.unwrap();
+        };
+
+        let psks = [psk];
+        let (_, server) = tokio::join!(client, server_handshake(&mut sr, &mut sw, &psks));
+        let err = server.err().unwrap();
+        assert_eq!(err.to_string(), "Authentication failed");
+    }
+
+    #[tokio::test]
+    async fn server_that_cannot_prove_the_token_is_rejected() {
+        let ((mut cr, mut cw), (mut sr, mut sw)) = pipe();
+
+        // Accepts any client, answering with a MAC under another token.
+        let server = async {
+            let hello = protocol::read_plain_frame(&mut sr).await.unwrap();
+            let client_public: [u8; 32] = hello.data.as_slice().try_into().unwrap();
+            let (secret, server_public) = crypto::generate_ephemeral();
+            let challenge = Frame {
+                frame_type: FrameType::AuthChallenge,
+                conn_id: 0,
+                data: server_public.to_vec(),
+            };
+            protocol::write_plain_frame(&mut sw, &challenge).await.unwrap();
+
+            let transcript = crypto::transcript_hash(&client_public, &server_public);
+            let (client_to_server, server_to_client) =
+                crypto::derive_session_keys(secret, &client_public, &transcript).unwrap();
+            let mut recv = FrameCipher::new(&client_to_server, transcript);
+            let mut send = FrameCipher::new(&server_to_client, transcript);
+            protocol::read_frame(&mut sr, &mut recv).await.unwrap();
+
+            let wrong_psk = crypto::derive_psk("mallory");
+            let mut data = vec![0x00];
+            data.extend_from_slice(&crypto::auth_mac(&wrong_psk, SERVER_MAC_LABEL, &transcript));
+            data.extend_from_slice(&encode_version());
+            let response = Frame {
+                frame_type: FrameType::AuthResult,
+                conn_id: 0,
+                data,
+            };
+            protocol::write_frame(&mut sw, &mut send, &response).await.unwrap();
+        };
+
+        let (client, _) = tokio::join!(client_handshake(&mut cr, &mut cw, "alice"), server);
+        let err = client.err().unwrap();
+        assert_eq!(err.to_string(), "Server failed to prove knowledge of the token");
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+use tracing::{debug, warn};
+
+use crate::config::HeartbeatConfig;
+use crate::handshake::Negotiated;
+use crate::protocol::{Frame, FrameSender, FrameType, CAP_HEARTBEAT};
+
+// Both sides ping each other. A Ping carries the time it was sent, in
+// microseconds since the session started, and the Pong echoes it back, so
+// the RTT is known without remembering outstanding pings. Pings go on the
+// control queue so Data waiting on this side doesn't delay them.
+//
+// The timeout counts from the oldest Ping still waiting for its Pong, so a
+// session is never torn down before it has sent one, whatever the interval.
+pub struct Heartbeat {
+    start: Instant,
+    // None when pings are turned off or the peer can't answer them.
+    ticker: Option<Interval>,
+    timeout: Duration,
+    // Send time of each unanswered Ping, oldest first, as carried in the
+    // Ping and as an Instant.
+    unanswered: VecDeque<(u64, Instant)>,
+    rtt: Option<Duration>,
+}
+
+impl Heartbeat {
+    pub fn new(config: &HeartbeatConfig, negotiated: &Negotiated) -> Self {
+        let start = Instant::now();
+        let enabled = config.interval_ms > 0 && negotiated.supports(CAP_HEARTBEAT);
+        let ticker = enabled.then(|| {
+            let interval = Duration::from_millis(config.interval_ms);
+            let mut ticker = tokio::time::interval_at(start + interval, interval);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            ticker
+        });
+        Heartbeat {
+            start,
+            ticker,
+            timeout: Duration::from_millis(config.timeout_ms),
+            unanswered: VecDeque::new(),
+            rtt: None,
+        }
+    }
+
+    // Sends the next Ping once it is due. Fails as soon as the oldest
+    // unanswered Ping is `timeout` old, meaning the session should be torn
+    // down.
+    pub async fn tick(&mut self, tx: &FrameSender) -> anyhow::Result<()> {
+        let Some(ticker) = &mut self.ticker else {
+            return std::future::pending().await;
+        };
+        let deadline = self.unanswered.front().map(|&(_, oldest)| oldest + self.timeout);
+        let timed_out = async {
+            match deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            biased;
+            () = timed_out => {
+                let silence = self.unanswered.front().map_or(Duration::ZERO, |&(_, oldest)| {
+                    oldest.elapsed()
+                });
+                let last_rtt = match self.rtt {
+                    Some(rtt) => format!("{:.1} ms", rtt.as_secs_f64() * 1000.0),
+                    None => "none".to_string(),
+                };
+                return Err(anyhow::anyhow!(
+                    "No heartbeat reply for {:.1}s (last RTT {})",
+                    silence.as_secs_f64(),
+                    last_rtt
+                ));
+            }
+            _ = ticker.tick() => {}
+        }
+
+        let sent = self.start.elapsed().as_micros() as u64;
+        self.unanswered.push_back((sent, Instant::now()));
+        tx.send_control(Frame {
+            frame_type: FrameType::Ping,
+            conn_id: 0,
+            data: sent.to_be_bytes().to_vec(),
+        })
+    }
+
+    pub fn handle_pong(&mut self, frame: &Frame) {
+        let Ok(sent) = <[u8; 8]>::try_from(frame.data.as_slice()) else {
+            warn!("Invalid Pong frame");
+            return;
+        };
+        let sent = Duration::from_micros(u64::from_be_bytes(sent));
+        let Some(rtt) = self.start.elapsed().checked_sub(sent) else {
+            warn!("Pong for a Ping that was never sent");
+            return;
+        };
+        // A Pong answers its own Ping and any older ones still outstanding.
+        let sent_micros = sent.as_micros() as u64;
+        while self
+            .unanswered
+            .front()
+            .is_some_and(|&(pending, _)| pending <= sent_micros)
+        {
+            self.unanswered.pop_front();
+        }
+        self.rtt = Some(rtt);
+        debug!("Heartbeat RTT {:.1} ms", rtt.as_secs_f64() * 1000.0);
+    }
+}
+
+// Answers the peer's Ping.
+pub fn send_pong(tx: &FrameSender, ping: Frame) {
+    let _ = tx.send_control(Frame {
+        frame_type: FrameType::Pong,
+        conn_id: 0,
+        data: ping.data,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{frame_channel, CAPABILITIES};
+
+    fn heartbeat(interval_ms: u64, timeout_ms: u64) -> Heartbeat {
+        let config = HeartbeatConfig {
+            interval_ms,
+            timeout_ms,
+        };
+        let negotiated = Negotiated {
+            version: 1,
+            capabilities: CAPABILITIES,
+        };
+        Heartbeat::new(&config, &negotiated)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn timeout_shorter_than_interval_keeps_a_healthy_session() {
+        let (tx, mut rx) = frame_channel();
+        let mut heartbeat = heartbeat(30000, 10000);
+
+        for _ in 0..3 {
+            heartbeat.tick(&tx).await.unwrap();
+            let ping = rx.recv().await.unwrap();
+            assert!(matches!(ping.frame_type, FrameType::Ping));
+            heartbeat.handle_pong(&Frame {
+                frame_type: FrameType::Pong,
+                conn_id: 0,
+                data: ping.data,
+            });
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dead_peer_is_detected_timeout_after_the_first_ping() {
+        let (tx, _rx) = frame_channel();
+        let mut heartbeat = heartbeat(15000, 45000);
+        let start = Instant::now();
+
+        while heartbeat.tick(&tx).await.is_ok() {}
+        assert_eq!(start.elapsed(), Duration::from_millis(15000 + 45000));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unanswered_ping_times_out() {
+        let (tx, _rx) = frame_channel();
+        let mut heartbeat = heartbeat(30000, 10000);
+
+        heartbeat.tick(&tx).await.unwrap();
+        let err = heartbeat.tick(&tx).await.unwrap_err();
+        assert!(err.to_string().contains("No heartbeat reply"), "{}", err);
+    }
+}
@@ -0,0 +1,528 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use base64::Engine;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+// Hop-by-hop headers dropped when a plain HTTP request is passed on, along
+// with any named in its Connection header (RFC 9110, section 7.6.1).
+// Transfer-Encoding is hop-by-hop too, but the body is passed on as it came
+// in, so its framing headers are kept.
+const HOP_HEADERS: &[&str] = &[
+    "proxy-authorization",
+    "proxy-connection",
+    "connection",
+    "keep-alive",
+    "te",
+    "trailer",
+    "upgrade",
+];
+const FRAMING_HEADERS: &[&str] = &["content-length", "transfer-encoding"];
+
+// A request read from a local HTTP proxy client. For CONNECT the caller
+// answers with `reply` once the tunneled connection is up; a plain HTTP
+// request is rewritten to origin form and sent on as `initial_data`, and the
+// target's response goes straight back to the client.
+pub struct ProxyRequest {
+    pub target: String,
+    pub is_connect: bool,
+    // Bytes to send to the target before anything else read from the client.
+    pub initial_data: Vec<u8>,
+    // How much more of the client's stream belongs to the request.
+    body: BodyEnd,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Reply {
+    Established,
+    Forbidden,
+    BadGateway,
+    ServiceUnavailable,
+    GatewayTimeout,
+}
+
+impl ProxyRequest {
+    pub async fn reply(&self, stream: &mut TcpStream, reply: Reply) -> anyhow::Result<()> {
+        match reply {
+            Reply::Established if self.is_connect => {
+                write_status(stream, "200 Connection established", &[]).await
+            }
+            Reply::Established => Ok(()),
+            Reply::Forbidden => write_status(stream, "403 Forbidden", &[]).await,
+            Reply::BadGateway => write_status(stream, "502 Bad Gateway", &[]).await,
+            Reply::ServiceUnavailable => {
+                write_status(stream, "503 Service Unavailable", &[]).await
+            }
+            Reply::GatewayTimeout => write_status(stream, "504 Gateway Timeout", &[]).await,
+        }
+    }
+
+    // Wraps the client's socket for relaying to the target. After a plain
+    // request, reading stops at the end of its body: the target only gets
+    // this one request, so anything the client pipelines behind it is
+    // dropped, and the client retries it once the connection closes.
+    pub fn client_stream<S>(&self, stream: S) -> ClientStream<S> {
+        ClientStream {
+            inner: stream,
+            body: self.body,
+        }
+    }
+}
+
+// Reads one request head and works out where it should go. With
+// `credentials` set, the request must carry matching Basic
+// Proxy-Authorization. Absolute-URI requests are only accepted when
+// `allow_plain` is set.
+pub async fn accept(
+    stream: &mut TcpStream,
+    credentials: Option<(&str, &str)>,
+    allow_plain: bool,
+) -> anyhow::Result<ProxyRequest> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 4096];
+    let (head_len, method, uri, headers) = loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(anyhow::anyhow!("Connection closed before request head"));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+
+        let mut parsed_headers = [httparse::EMPTY_HEADER; 64];
+        let mut request = httparse::Request::new(&mut parsed_headers);
+        match request.parse(&buf) {
+            Ok(httparse::Status::Complete(len)) => {
+                let method = request.method.unwrap_or_default().to_string();
+                let uri = request.path.unwrap_or_default().to_string();
+                let headers: Vec<(String, Vec<u8>)> = request
+                    .headers
+                    .iter()
+                    .map(|h| (h.name.to_string(), h.value.to_vec()))
+                    .collect();
+                break (len, method, uri, headers);
+            }
+            Ok(httparse::Status::Partial) if buf.len() < MAX_HEAD_SIZE => continue,
+            Ok(httparse::Status::Partial) => {
+                write_status(stream, "431 Request Header Fields Too Large", &[]).await?;
+                return Err(anyhow::anyhow!("HTTP request head too large"));
+            }
+            Err(e) => {
+                write_status(stream, "400 Bad Request", &[]).await?;
+                return Err(anyhow::anyhow!("Invalid HTTP request: {}", e));
+            }
+        }
+    };
+
+    if let Some((username, password)) = credentials {
+        let expected = format!("{}:{}", username, password);
+        let authorized = headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("proxy-authorization"))
+            .and_then(|(_, value)| basic_credentials(value))
+            .is_some_and(|given| given == expected.as_bytes());
+        if !authorized {
+            let challenge = "Proxy-Authenticate: Basic realm=\"kproxy\"";
+            write_status(stream, "407 Proxy Authentication Required", &[challenge]).await?;
+            return Err(anyhow::anyhow!("HTTP proxy authentication failed"));
+        }
+    }
+
+    let mut leftover = buf[head_len..].to_vec();
+
+    if method.eq_ignore_ascii_case("CONNECT") {
+        if !uri.contains(':') {
+            write_status(stream, "400 Bad Request", &[]).await?;
+            return Err(anyhow::anyhow!("CONNECT target without a port: {}", uri));
+        }
+        return Ok(ProxyRequest {
+            target: uri,
+            is_connect: true,
+            initial_data: leftover,
+            body: BodyEnd::Unlimited,
+        });
+    }
+
+    if !allow_plain {
+        write_status(stream, "405 Method Not Allowed", &["Allow: CONNECT"]).await?;
+        return Err(anyhow::anyhow!("Plain HTTP proxying is disabled ({} {})", method, uri));
+    }
+    let Some((authority, path)) = split_absolute_uri(&uri) else {
+        write_status(stream, "400 Bad Request", &[]).await?;
+        return Err(anyhow::anyhow!("Unsupported request URI: {}", uri));
+    };
+    let mut body = match request_body(&headers) {
+        Ok(body) => body,
+        Err(e) => {
+            write_status(stream, "400 Bad Request", &[]).await?;
+            return Err(e);
+        }
+    };
+    let chunked = matches!(body, BodyEnd::Chunked(_));
+    let target = if authority.ends_with(']') || !authority.contains(':') {
+        format!("{}:80", authority)
+    } else {
+        authority.to_string()
+    };
+
+    // Rewrite to origin form and ask the target to close after responding, so
+    // later requests on the client's connection, possibly for other hosts,
+    // come in on a new one.
+    let mut head = format!("{} {} HTTP/1.1\r\n", method, path).into_bytes();
+    let mut has_host = false;
+    let connection_options = connection_options(&headers);
+    for (name, value) in &headers {
+        let lower = name.to_ascii_lowercase();
+        if HOP_HEADERS.contains(&lower.as_str()) || connection_options.contains(&lower) {
+            continue;
+        }
+        // The chunked encoding decides where the body ends, for the target
+        // as it does here.
+        if chunked && lower == "content-length" {
+            continue;
+        }
+        has_host |= lower == "host";
+        head.extend_from_slice(name.as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(value);
+        head.extend_from_slice(b"\r\n");
+    }
+    if !has_host {
+        head.extend_from_slice(format!("Host: {}\r\n", authority).as_bytes());
+    }
+    head.extend_from_slice(b"Connection: close\r\n\r\n");
+    let body_len = body.take(&leftover);
+    leftover.truncate(body_len);
+    head.extend_from_slice(&leftover);
+
+    Ok(ProxyRequest {
+        target,
+        is_connect: false,
+        initial_data: head,
+        body,
+    })
+}
+
+// The header names listed in the Connection headers, lowercased, other than
+// the ones the body's framing depends on.
+fn connection_options(headers: &[(String, Vec<u8>)]) -> Vec<String> {
+    headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("connection"))
+        .flat_map(|(_, value)| value.split(|&b| b == b','))
+        .map(|option| String::from_utf8_lossy(option.trim_ascii()).to_ascii_lowercase())
+        .filter(|option| !option.is_empty() && !FRAMING_HEADERS.contains(&option.as_str()))
+        .collect()
+}
+
+// Works out how a plain request's body is delimited (RFC 9112, section 6).
+fn request_body(headers: &[(String, Vec<u8>)]) -> anyhow::Result<BodyEnd> {
+    let mut transfer_encoding = None;
+    let mut content_length = None;
+    for (name, value) in headers {
+        if name.eq_ignore_ascii_case("transfer-encoding") {
+            transfer_encoding = Some(value);
+        } else if name.eq_ignore_ascii_case("content-length") {
+            let length = std::str::from_utf8(value)
+                .ok()
+                .and_then(|v| v.trim().parse::<u64>().ok())
+                .ok_or_else(|| anyhow::anyhow!("Invalid Content-Length"))?;
+            if content_length.is_some_and(|l| l != length) {
+                return Err(anyhow::anyhow!("Conflicting Content-Length headers"));
+            }
+            content_length = Some(length);
+        }
+    }
+
+    if let Some(value) = transfer_encoding {
+        let last = value.rsplit(|&b| b == b',').next().unwrap_or_default();
+        if !last.trim_ascii().eq_ignore_ascii_case(b"chunked") {
+            return Err(anyhow::anyhow!("Unsupported Transfer-Encoding"));
+        }
+        return Ok(BodyEnd::Chunked(Chunk::Size(0)));
+    }
+    Ok(BodyEnd::Length(content_length.unwrap_or(0)))
+}
+
+// Where the request's body ends in the client's stream.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BodyEnd {
+    // CONNECT: everything is passed on.
+    Unlimited,
+    Length(u64),
+    Chunked(Chunk),
+}
+
+// Position within a chunked body. Malformed chunk sizes end the body, which
+// leaves the target with a truncated request to refuse.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Chunk {
+    Size(u64),
+    Extension(u64),
+    Data(u64),
+    DataEnd,
+    Trailer { line_empty: bool },
+    Done,
+}
+
+impl BodyEnd {
+    // Returns how many bytes at the start of `data` are still part of the
+    // body.
+    fn take(&mut self, data: &[u8]) -> usize {
+        match self {
+            BodyEnd::Unlimited => data.len(),
+            BodyEnd::Length(remaining) => {
+                let n = (*remaining).min(data.len() as u64);
+                *remaining -= n;
+                n as usize
+            }
+            BodyEnd::Chunked(chunk) => {
+                let mut taken = 0;
+                while taken < data.len() {
+                    match chunk {
+                        Chunk::Done => break,
+                        Chunk::Data(remaining) => {
+                            let n = (*remaining).min((data.len() - taken) as u64);
+                            taken += n as usize;
+                            *remaining -= n;
+                            if *remaining == 0 {
+                                *chunk = Chunk::DataEnd;
+                            }
+                        }
+                        _ => {
+                            *chunk = chunk.next(data[taken]);
+                            taken += 1;
+                        }
+                    }
+                }
+                taken
+            }
+        }
+    }
+}
+
+impl Chunk {
+    fn next(self, byte: u8) -> Chunk {
+        match (self, byte) {
+            (Chunk::Size(0) | Chunk::Extension(0), b'\n') => Chunk::Trailer { line_empty: true },
+            (Chunk::Size(size) | Chunk::Extension(size), b'\n') => Chunk::Data(size),
+            (Chunk::Size(size), b'\r') => Chunk::Size(size),
+            (Chunk::Size(size), _) => match (byte as char).to_digit(16) {
+                Some(digit) => size
+                    .checked_mul(16)
+                    .and_then(|size| size.checked_add(digit as u64))
+                    .map_or(Chunk::Done, Chunk::Size),
+                None => Chunk::Extension(size),
+            },
+            (Chunk::Extension(size), _) => Chunk::Extension(size),
+            (Chunk::DataEnd, b'\n') => Chunk::Size(0),
+            (Chunk::Trailer { line_empty: true }, b'\n') => Chunk::Done,
+            (Chunk::Trailer { .. }, b'\n') => Chunk::Trailer { line_empty: true },
+            (Chunk::Trailer { line_empty }, b'\r') => Chunk::Trailer { line_empty },
+            (Chunk::Trailer { .. }, _) => Chunk::Trailer { line_empty: false },
+            (chunk, _) => chunk,
+        }
+    }
+}
+
+// The client's socket as relayed to the target: reads end with the
+// request's body, after which whatever else the client sends is read and
+// dropped. Writes pass straight through.
+pub struct ClientStream<S> {
+    inner: S,
+    body: BodyEnd,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ClientStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            let start = buf.filled().len();
+            ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+            let read = buf.filled().len() - start;
+            let taken = this.body.take(&buf.filled()[start..]);
+            buf.set_filled(start + taken);
+            if read == 0 || taken > 0 {
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ClientStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+// Splits `http://authority/path` into the authority (without any userinfo)
+// and the origin-form path.
+fn split_absolute_uri(uri: &str) -> Option<(&str, String)> {
+    let scheme_len = "http://".len();
+    if uri.len() < scheme_len || !uri[..scheme_len].eq_ignore_ascii_case("http://") {
+        return None;
+    }
+    let rest = &uri[scheme_len..];
+    let end = rest.find(['/', '?']).unwrap_or(rest.len());
+    let authority = rest[..end].rsplit('@').next().unwrap_or_default();
+    if authority.is_empty() {
+        return None;
+    }
+    let path = match &rest[end..] {
+        p if p.starts_with('/') => p.to_string(),
+        p => format!("/{}", p),
+    };
+    Some((authority, path))
+}
+
+fn basic_credentials(value: &[u8]) -> Option<Vec<u8>> {
+    let value = std::str::from_utf8(value).ok()?.trim();
+    let (scheme, encoded) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    base64::engine::general_purpose::STANDARD.decode(encoded.trim()).ok()
+}
+
+async fn write_status(
+    stream: &mut TcpStream,
+    status: &str,
+    headers: &[&str],
+) -> anyhow::Result<()> {
+    let mut response = format!("HTTP/1.1 {}\r\n", status);
+    for header in headers {
+        response.push_str(header);
+        response.push_str("\r\n");
+    }
+    if !status.starts_with("200") {
+        response.push_str("Content-Length: 0\r\nConnection: close\r\n");
+    }
+    response.push_str("\r\n");
+    stream.write_all(response.as_bytes()).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn headers(list: &[(&str, &str)]) -> Vec<(String, Vec<u8>)> {
+        list.iter().map(|(name, value)| (name.to_string(), value.as_bytes().to_vec())).collect()
+    }
+
+    #[test]
+    fn body_length_from_headers() {
+        let body = request_body(&headers(&[("Host", "a")])).unwrap();
+        assert_eq!(body, BodyEnd::Length(0));
+        let body = request_body(&headers(&[("Content-Length", " 12 ")])).unwrap();
+        assert_eq!(body, BodyEnd::Length(12));
+        let both = [("Content-Length", "12"), ("Transfer-Encoding", "gzip, chunked")];
+        let body = request_body(&headers(&both)).unwrap();
+        assert_eq!(body, BodyEnd::Chunked(Chunk::Size(0)));
+
+        assert!(request_body(&headers(&[("Content-Length", "x")])).is_err());
+        let conflicting = [("Content-Length", "1"), ("Content-Length", "2")];
+        assert!(request_body(&headers(&conflicting)).is_err());
+        assert!(request_body(&headers(&[("Transfer-Encoding", "gzip")])).is_err());
+    }
+
+    #[test]
+    fn content_length_ends_the_body() {
+        let mut body = BodyEnd::Length(5);
+        assert_eq!(body.take(b"hel"), 3);
+        assert_eq!(body.take(b"loGET / HTTP/1.1\r\n"), 2);
+        assert_eq!(body.take(b"more"), 0);
+        assert_eq!(BodyEnd::Unlimited.take(b"more"), 4);
+    }
+
+    #[test]
+    fn chunked_body_ends_after_trailers() {
+        let chunked: &[u8] =
+            b"4\r\nWiki\r\n5;ext=1\r\npedia\r\nA\r\n0123456789\r\n0\r\nX-T: 1\r\n\r\n";
+        let data = [chunked, b"GET http://b/ HTTP/1.1\r\n\r\n"].concat();
+
+        let mut body = BodyEnd::Chunked(Chunk::Size(0));
+        assert_eq!(body.take(&data), chunked.len());
+        assert_eq!(body.take(b"more"), 0);
+
+        // Split at every byte.
+        let mut body = BodyEnd::Chunked(Chunk::Size(0));
+        let taken: usize = data.iter().map(|&b| body.take(&[b])).sum();
+        assert_eq!(taken, chunked.len());
+
+        let mut body = BodyEnd::Chunked(Chunk::Size(0));
+        assert_eq!(body.take(b"0\r\n\r\nrest"), 5);
+        let mut body = BodyEnd::Chunked(Chunk::Size(0));
+        assert_eq!(body.take(b"fffffffffffffffff\r\n"), 17);
+        assert_eq!(body, BodyEnd::Chunked(Chunk::Done));
+    }
+
+    #[tokio::test]
+    async fn pipelined_requests_are_not_passed_on() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+
+        let first = "POST http://a.example/x HTTP/1.1\r\nProxy-Authorization: Basic dTpw\r\n\
+                     Content-Length: 8\r\n\r\n";
+        let second = "GET http://b.example/ HTTP/1.1\r\nProxy-Authorization: Basic dTpw\r\n\r\n";
+        client.write_all(format!("{}body", first).as_bytes()).await.unwrap();
+
+        let request = accept(&mut stream, Some(("u", "p")), true).await.unwrap();
+        assert_eq!(request.target, "a.example:80");
+        let head = String::from_utf8(request.initial_data.clone()).unwrap();
+        assert!(head.starts_with("POST /x HTTP/1.1\r\n"), "{}", head);
+        assert!(head.ends_with("\r\n\r\nbody"), "{}", head);
+        assert!(!head.contains("Proxy-Authorization"), "{}", head);
+
+        // The rest of the body arrives later; the second request is dropped.
+        let mut relayed = request.client_stream(stream);
+        client.write_all(b"tail").await.unwrap();
+        client.write_all(second.as_bytes()).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut rest = Vec::new();
+        relayed.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"tail");
+    }
+
+    #[tokio::test]
+    async fn hop_by_hop_headers_are_dropped() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+
+        let request = "GET http://a.example/ HTTP/1.1\r\nHost: a.example\r\n\
+                       Connection: Keep-Alive, X-Hop , transfer-encoding\r\nX-Hop: 1\r\n\
+                       Upgrade: websocket\r\nTE: trailers\r\nTrailer: X-T\r\n\
+                       Transfer-Encoding: chunked\r\nX-End: 2\r\n\r\n";
+        client.write_all(request.as_bytes()).await.unwrap();
+
+        let request = accept(&mut stream, None, true).await.unwrap();
+        let head = String::from_utf8(request.initial_data).unwrap();
+        assert_eq!(
+            head,
+            "GET / HTTP/1.1\r\nHost: a.example\r\nTransfer-Encoding: chunked\r\n\
+             X-End: 2\r\nConnection: close\r\n\r\n"
+        );
+    }
+}
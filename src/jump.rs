@@ -0,0 +1,117 @@
+use std::sync::Arc;
+
+use tokio::io::DuplexStream;
+use tokio::sync::mpsc;
+use tracing::{Instrument, info};
+
+use crate::config::JumpHostConfig;
+use crate::handshake;
+use crate::heartbeat;
+use crate::protocol::{self, Frame, FrameSender, FrameType};
+use crate::tls::BoxedStream;
+use crate::tunnel::{self, Connections, PendingConnection};
+
+// A kproxy server as a hop on the way to another: `connect` opens a session
+// with the jump host on an established connection to it and asks it for a
+// dynamically targeted connection to the next hop, like a SOCKS CONNECT on
+// the client would. The caller gets one end of an in-memory pipe whose other
+// end is relayed over that connection; the session lasts as long as the pipe.
+
+// The only connection on the jump host session.
+const CONN_ID: u32 = 1;
+
+const PIPE_SIZE: usize = 64 * 1024;
+
+pub async fn connect(
+    stream: BoxedStream,
+    config: &JumpHostConfig,
+    target: &str,
+) -> anyhow::Result<BoxedStream> {
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let (ciphers, negotiated) = handshake::client_handshake(&mut reader, &mut writer, &config.token)
+        .await
+        .map_err(|e| anyhow::anyhow!("Jump host {}: {}", config.addr, e))?;
+
+    let (tx, rx) = protocol::frame_channel();
+    tunnel::spawn_writer(writer, ciphers.send, rx);
+    let (mut frames, _) = tunnel::spawn_reader(reader, ciphers.recv);
+    let half_close = negotiated.supports(protocol::CAP_HALF_CLOSE);
+    let connections = Arc::new(Connections::new(None, half_close));
+
+    let mut data = vec![0x01];
+    data.extend_from_slice(target.as_bytes());
+    let pending = connections.open(CONN_ID, data, &tx).await?;
+
+    // Everything is torn down by dropping it if the jump host can't connect.
+    loop {
+        let frame = frames
+            .recv()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Jump host {} closed the session", config.addr))??;
+        match frame.frame_type {
+            FrameType::NewConnectionResult => break,
+            FrameType::CloseConnection => {
+                let (reason, message) = frame.close_reason();
+                return Err(anyhow::anyhow!(
+                    "Jump host {} could not connect to {}: {}",
+                    config.addr,
+                    target,
+                    reason.describe(&message)
+                ));
+            }
+            FrameType::Data => connections.route_data(&tx, frame),
+            FrameType::WindowUpdate => connections.handle_window_update(&tx, frame),
+            FrameType::Fin => connections.handle_fin(CONN_ID),
+            FrameType::Ping => heartbeat::send_pong(&tx, frame),
+            _ => {}
+        }
+    }
+
+    let (local, remote) = tokio::io::duplex(PIPE_SIZE);
+    let relay = relay(pending, remote, frames, connections, tx);
+    tokio::spawn(relay.in_current_span());
+    Ok(Box::new(local))
+}
+
+async fn relay(
+    pending: PendingConnection,
+    pipe: DuplexStream,
+    mut frames: mpsc::Receiver<anyhow::Result<Frame>>,
+    connections: Arc<Connections>,
+    tx: FrameSender,
+) {
+    let run = pending.run(pipe);
+    tokio::pin!(run);
+    let reason = loop {
+        tokio::select! {
+            // Our end of the pipe was closed.
+            reason = &mut run => break reason,
+            frame = frames.recv() => match frame {
+                Some(Ok(frame)) => match frame.frame_type {
+                    FrameType::Data => connections.route_data(&tx, frame),
+                    FrameType::WindowUpdate => connections.handle_window_update(&tx, frame),
+                    FrameType::Fin => connections.handle_fin(CONN_ID),
+                    // The jump host's side is done, and so is the pipe.
+                    FrameType::CloseConnection => {
+                        connections.remove(CONN_ID);
+                    }
+                    // Answered so the jump host doesn't time the session out;
+                    // the hops around it notice a dead link on their own.
+                    FrameType::Ping => heartbeat::send_pong(&tx, frame),
+                    _ => {}
+                },
+                _ => {
+                    info!("Jump host session ended");
+                    connections.clear();
+                    run.await;
+                    return;
+                }
+            },
+        }
+    };
+
+    // Unless the jump host closed the connection, it's told.
+    if connections.remove(CONN_ID) {
+        let _ = tx.send(Frame::close(CONN_ID, reason, "")).await;
+    }
+}
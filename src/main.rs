@@ -1,11 +1,28 @@
 use clap::{Parser, Subcommand};
 
+mod acl;
 mod client;
 mod config;
 mod crypto;
+mod handshake;
+mod heartbeat;
+mod http_connect;
+mod http_proxy;
+mod jump;
 mod protocol;
+mod quic;
+mod reload;
 mod server;
+mod shutdown;
 mod socks5;
+mod socks_server;
+mod tls;
+mod transport;
+mod tunnel;
+mod udp;
+mod users;
+mod util;
+mod websocket;
 
 #[derive(Parser)]
 #[command(name = "kproxy", about = "TCP forwarding proxy with AES-256-GCM encryption")]
@@ -24,6 +41,10 @@ enum Commands {
         #[arg(short, long, default_value = "client.toml")]
         config: String,
     },
+    Reload {
+        #[arg(short, long)]
+        socket: String,
+    },
 }
 
 #[tokio::main]
@@ -38,13 +59,16 @@ async fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
 
     match cli.command {
-        Commands::Server { config } => {
-            let config = config::load_server_config(&config)?;
-            server::run(&config).await?;
+        Commands::Server { config: path } => {
+            let config = config::load_server_config(&path)?;
+            server::run(config, &path).await?;
+        }
+        Commands::Client { config: path } => {
+            let config = config::load_client_config(&path)?;
+            client::run(config, &path).await?;
         }
-        Commands::Client { config } => {
-            let config = config::load_client_config(&config)?;
-            client::run(&config).await?;
+        Commands::Reload { socket } => {
+            reload::send(&socket).await?;
         }
     }
 
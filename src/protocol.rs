@@ -1,5 +1,23 @@
 use anyhow::Result;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+
+use crate::crypto::FrameCipher;
+
+// Sent by both sides in the handshake. Sessions run at the lower of the two
+// versions, which must be at least MIN_PROTOCOL_VERSION.
+pub const PROTOCOL_VERSION: u16 = 1;
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+// Optional features, also exchanged in the handshake. Each side only uses
+// the ones both support.
+pub const CAP_HEARTBEAT: u32 = 1 << 0;
+pub const CAP_HALF_CLOSE: u32 = 1 << 1;
+pub const CAPABILITIES: u32 = CAP_HEARTBEAT | CAP_HALF_CLOSE;
+
+// Frame types from here up are optional: a peer that doesn't know one skips
+// it instead of ending the session. Types below it are required.
+pub const FIRST_OPTIONAL_FRAME_TYPE: u8 = 0x80;
 
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
@@ -11,6 +29,21 @@ pub enum FrameType {
     NewConnection = 0x05,
     Data = 0x06,
     CloseConnection = 0x07,
+    WindowUpdate = 0x08,
+    AuthChallenge = 0x09,
+    RegisterReverseForward = 0x0a,
+    NewConnectionResult = 0x0b,
+    NewUdpFlow = 0x0c,
+    UdpDatagram = 0x0d,
+    Ping = 0x0e,
+    Pong = 0x0f,
+    Fin = 0x10,
+    // The sender is shutting down: it won't accept new connections, and the
+    // open ones get a grace period to finish.
+    GoAway = 0x80,
+    // Payload: forward id. The forward stops taking new connections; the
+    // ones it has are left alone.
+    UnregisterForward = 0x81,
 }
 
 impl FrameType {
@@ -23,11 +56,86 @@ impl FrameType {
             0x05 => Some(FrameType::NewConnection),
             0x06 => Some(FrameType::Data),
             0x07 => Some(FrameType::CloseConnection),
+            0x08 => Some(FrameType::WindowUpdate),
+            0x09 => Some(FrameType::AuthChallenge),
+            0x0a => Some(FrameType::RegisterReverseForward),
+            0x0b => Some(FrameType::NewConnectionResult),
+            0x0c => Some(FrameType::NewUdpFlow),
+            0x0d => Some(FrameType::UdpDatagram),
+            0x0e => Some(FrameType::Ping),
+            0x0f => Some(FrameType::Pong),
+            0x10 => Some(FrameType::Fin),
+            0x80 => Some(FrameType::GoAway),
+            0x81 => Some(FrameType::UnregisterForward),
             _ => None,
         }
     }
 }
 
+// Why a connection ended: the first byte of a CloseConnection payload, which
+// a message for the logs may follow. An empty payload is a normal close, and
+// codes this build doesn't know read as ConnectFailed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CloseReason {
+    Normal = 0x00,
+    ConnectFailed = 0x01,
+    AclDenied = 0x02,
+    ConnectRefused = 0x03,
+    DnsFailure = 0x04,
+    Timeout = 0x05,
+    LimitExceeded = 0x06,
+    PeerReset = 0x07,
+    Shutdown = 0x08,
+    ProtocolError = 0x09,
+}
+
+impl CloseReason {
+    pub const ALL: [CloseReason; 10] = [
+        CloseReason::Normal,
+        CloseReason::ConnectFailed,
+        CloseReason::AclDenied,
+        CloseReason::ConnectRefused,
+        CloseReason::DnsFailure,
+        CloseReason::Timeout,
+        CloseReason::LimitExceeded,
+        CloseReason::PeerReset,
+        CloseReason::Shutdown,
+        CloseReason::ProtocolError,
+    ];
+
+    pub fn from_u8(v: u8) -> Self {
+        Self::ALL
+            .into_iter()
+            .find(|reason| *reason as u8 == v)
+            .unwrap_or(CloseReason::ConnectFailed)
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            CloseReason::Normal => "closed",
+            CloseReason::ConnectFailed => "connect failed",
+            CloseReason::AclDenied => "denied by ACL",
+            CloseReason::ConnectRefused => "connection refused",
+            CloseReason::DnsFailure => "DNS lookup failed",
+            CloseReason::Timeout => "timed out",
+            CloseReason::LimitExceeded => "limit exceeded",
+            CloseReason::PeerReset => "connection reset",
+            CloseReason::Shutdown => "shutting down",
+            CloseReason::ProtocolError => "protocol error",
+        }
+    }
+
+    // The description followed by the peer's message, if it sent one.
+    pub fn describe(self, message: &str) -> String {
+        if message.is_empty() {
+            self.description().to_string()
+        } else {
+            format!("{}: {}", self.description(), message)
+        }
+    }
+}
+
 pub struct Frame {
     pub frame_type: FrameType,
     pub conn_id: u32,
@@ -35,6 +143,40 @@ pub struct Frame {
 }
 
 impl Frame {
+    pub fn close(conn_id: u32, reason: CloseReason, message: &str) -> Self {
+        let data = if reason == CloseReason::Normal && message.is_empty() {
+            Vec::new()
+        } else {
+            let mut data = vec![reason as u8];
+            data.extend_from_slice(message.as_bytes());
+            data
+        };
+        Frame {
+            frame_type: FrameType::CloseConnection,
+            conn_id,
+            data,
+        }
+    }
+
+    pub fn go_away() -> Self {
+        Frame {
+            frame_type: FrameType::GoAway,
+            conn_id: 0,
+            data: Vec::new(),
+        }
+    }
+
+    // The reason and message of a CloseConnection frame.
+    pub fn close_reason(&self) -> (CloseReason, String) {
+        match self.data.split_first() {
+            Some((&code, message)) => (
+                CloseReason::from_u8(code),
+                String::from_utf8_lossy(message).into_owned(),
+            ),
+            None => (CloseReason::Normal, String::new()),
+        }
+    }
+
     pub fn encode(&self) -> Vec<u8> {
         let mut buf = Vec::with_capacity(1 + 4 + self.data.len());
         buf.push(self.frame_type as u8);
@@ -59,48 +201,146 @@ impl Frame {
     }
 }
 
-pub async fn write_frame<W: AsyncWriteExt + Unpin>(
+// Handshake messages are exchanged before any keys exist, so they go out as
+// bare length-prefixed frames.
+pub async fn write_plain_frame<W: AsyncWriteExt + Unpin>(
     writer: &mut W,
-    key: &[u8; 32],
     frame: &Frame,
 ) -> Result<()> {
     let plaintext = frame.encode();
-    let encrypted = crate::crypto::encrypt(key, &plaintext)?;
-
-    let len = (encrypted.len() as u32).to_be_bytes();
+    let len = (plaintext.len() as u32).to_be_bytes();
     writer.write_all(&len).await?;
-    writer.write_all(&encrypted).await?;
+    writer.write_all(&plaintext).await?;
     writer.flush().await?;
     Ok(())
 }
 
-pub async fn read_frame<R: AsyncReadExt + Unpin>(reader: &mut R, key: &[u8; 32]) -> Result<Frame> {
+pub async fn read_plain_frame<R: AsyncReadExt + Unpin>(reader: &mut R) -> Result<Frame> {
     let mut len_buf = [0u8; 4];
     reader.read_exact(&mut len_buf).await?;
     let len = u32::from_be_bytes(len_buf) as usize;
 
-    if len > 1024 * 1024 * 64 {
-        return Err(anyhow::anyhow!("Frame too large: {} bytes", len));
+    if len > 4096 {
+        return Err(anyhow::anyhow!("Handshake frame too large: {} bytes", len));
     }
 
-    let mut encrypted = vec![0u8; len];
-    reader.read_exact(&mut encrypted).await?;
-
-    let plaintext = crate::crypto::decrypt(key, &encrypted)?;
+    let mut plaintext = vec![0u8; len];
+    reader.read_exact(&mut plaintext).await?;
     Frame::decode(&plaintext)
 }
 
-pub fn send_frame(
-    tx: &tokio::sync::mpsc::Sender<Vec<u8>>,
-    key: &[u8; 32],
+pub async fn write_frame<W: AsyncWriteExt + Unpin>(
+    writer: &mut W,
+    cipher: &mut FrameCipher,
     frame: &Frame,
 ) -> Result<()> {
     let plaintext = frame.encode();
-    let encrypted = crate::crypto::encrypt(key, &plaintext)?;
-    let mut raw = Vec::with_capacity(4 + encrypted.len());
-    raw.extend_from_slice(&(encrypted.len() as u32).to_be_bytes());
-    raw.extend_from_slice(&encrypted);
-    tx.try_send(raw)
-        .map_err(|e| anyhow::anyhow!("Channel send error: {}", e))?;
+    let encrypted = cipher.seal(&plaintext)?;
+
+    let len = (encrypted.len() as u32).to_be_bytes();
+    writer.write_all(&len).await?;
+    writer.write_all(&encrypted).await?;
+    writer.flush().await?;
     Ok(())
 }
+
+// Skips frames of optional types this build doesn't know.
+pub async fn read_frame<R: AsyncReadExt + Unpin>(
+    reader: &mut R,
+    cipher: &mut FrameCipher,
+) -> Result<Frame> {
+    loop {
+        let mut len_buf = [0u8; 4];
+        reader.read_exact(&mut len_buf).await?;
+        let len = u32::from_be_bytes(len_buf) as usize;
+
+        if len > 1024 * 1024 * 64 {
+            return Err(anyhow::anyhow!("Frame too large: {} bytes", len));
+        }
+
+        let mut encrypted = vec![0u8; len];
+        reader.read_exact(&mut encrypted).await?;
+
+        let plaintext = cipher.open(&encrypted)?;
+        if let Some(&frame_type) = plaintext.first()
+            && frame_type >= FIRST_OPTIONAL_FRAME_TYPE
+            && FrameType::from_u8(frame_type).is_none()
+        {
+            continue;
+        }
+        return Frame::decode(&plaintext);
+    }
+}
+
+// Frames are queued unencrypted and sealed by the single writer task, which
+// keeps sequence numbers in the same order as the bytes on the wire.
+//
+// Data frames go through a bounded queue so a fast local socket is slowed down
+// instead of buffering without limit. Control frames (window updates, closes,
+// handshake replies) use their own unbounded queue: they are small, and the
+// dispatch loop must never block on them or both peers can deadlock.
+//
+// Control frames overtake queued Data frames, so the CloseConnection that ends
+// a stream has to go through the data queue, behind that stream's last data.
+#[derive(Clone)]
+pub struct FrameSender {
+    data_tx: mpsc::Sender<Frame>,
+    control_tx: mpsc::UnboundedSender<Frame>,
+}
+
+pub struct FrameReceiver {
+    data_rx: mpsc::Receiver<Frame>,
+    control_rx: mpsc::UnboundedReceiver<Frame>,
+}
+
+pub fn frame_channel() -> (FrameSender, FrameReceiver) {
+    let (data_tx, data_rx) = mpsc::channel(4096);
+    let (control_tx, control_rx) = mpsc::unbounded_channel();
+    (
+        FrameSender {
+            data_tx,
+            control_tx,
+        },
+        FrameReceiver {
+            data_rx,
+            control_rx,
+        },
+    )
+}
+
+impl FrameSender {
+    pub async fn send(&self, frame: Frame) -> Result<()> {
+        self.data_tx
+            .send(frame)
+            .await
+            .map_err(|_| anyhow::anyhow!("Control connection closed"))
+    }
+
+    // For datagrams, which are better dropped than queued when the tunnel is
+    // backed up.
+    pub fn try_send(&self, frame: Frame) -> Result<()> {
+        self.data_tx
+            .try_send(frame)
+            .map_err(|_| anyhow::anyhow!("Data queue full or closed"))
+    }
+
+    pub fn send_control(&self, frame: Frame) -> Result<()> {
+        self.control_tx
+            .send(frame)
+            .map_err(|_| anyhow::anyhow!("Control connection closed"))
+    }
+
+    pub async fn closed(&self) {
+        self.data_tx.closed().await
+    }
+}
+
+impl FrameReceiver {
+    pub async fn recv(&mut self) -> Option<Frame> {
+        tokio::select! {
+            biased;
+            Some(frame) = self.control_rx.recv() => Some(frame),
+            frame = self.data_rx.recv() => frame,
+        }
+    }
+}
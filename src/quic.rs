@@ -0,0 +1,290 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use quinn::{Connection, Endpoint, IdleTimeout, TransportConfig, VarInt};
+use tokio::net::lookup_host;
+use tokio::sync::mpsc;
+use tracing::{info, warn, Instrument};
+
+use crate::config::{ClientQuicConfig, ClientTlsConfig, ServerQuicConfig, ServerTlsConfig};
+use crate::protocol::{self, Frame, FrameType};
+use crate::tls::{self, BoxedStream};
+use crate::transport::{Accepted, BoxFuture, Link, Listener, Transport};
+
+// Over QUIC the first bidirectional stream the client opens is the control
+// connection and carries the same handshake and frames as a TCP one. Every
+// tunneled TCP connection gets a stream of its own instead of Data frames,
+// opened by whichever side opens the connection with its NewConnection frame
+// as a plain header, so a lost packet only stalls the connection it belongs
+// to and QUIC does the flow control. Those streams are protected by QUIC's
+// TLS alone, which is why the server certificate always has to verify.
+
+const ALPN: &[u8] = b"kproxy";
+
+// Concurrent tunneled connections per session; opening more waits for one
+// to end.
+const MAX_STREAMS: u32 = 1024;
+
+// How long the peer gets to send the header of a stream it opened.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct QuicTransport {
+    config: quinn::ClientConfig,
+    server_addr: String,
+    server_name: String,
+}
+
+impl QuicTransport {
+    pub fn new(
+        quic: &ClientQuicConfig,
+        tls: Option<&ClientTlsConfig>,
+        server_addr: &str,
+    ) -> anyhow::Result<Self> {
+        let default_tls = ClientTlsConfig::default();
+        let tls = tls.unwrap_or(&default_tls);
+        let mut tls_config = tls::client_config(tls)?;
+        tls_config.alpn_protocols = vec![ALPN.to_vec()];
+        let crypto = QuicClientConfig::try_from(tls_config)
+            .map_err(|e| anyhow::anyhow!("Invalid QUIC TLS configuration: {}", e))?;
+
+        let mut transport = transport_config(quic.idle_timeout_ms)?;
+        transport.keep_alive_interval(Some(Duration::from_millis(quic.keep_alive_interval_ms)));
+        let mut config = quinn::ClientConfig::new(Arc::new(crypto));
+        config.transport_config(Arc::new(transport));
+
+        Ok(QuicTransport {
+            config,
+            server_addr: server_addr.to_string(),
+            server_name: tls::server_name(tls, server_addr)?.to_str().into_owned(),
+        })
+    }
+}
+
+impl Transport for QuicTransport {
+    // The endpoint's socket is bound to the wildcard address, so when the
+    // local address changes the connection migrates to the new path.
+    fn connect(&self) -> BoxFuture<'_, anyhow::Result<Link>> {
+        Box::pin(async move {
+            let addr = lookup_host(&self.server_addr)
+                .await?
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("No addresses found for {}", self.server_addr))?;
+            let bind: SocketAddr = if addr.is_ipv6() {
+                "[::]:0".parse()?
+            } else {
+                "0.0.0.0:0".parse()?
+            };
+            let endpoint = Endpoint::client(bind)?;
+            let connection = endpoint
+                .connect_with(self.config.clone(), addr, &self.server_name)?
+                .await?;
+
+            let (send, recv) = connection.open_bi().await?;
+            info!("Connected to server {} over QUIC", self.server_addr);
+            Ok(Link {
+                stream: Box::new(tokio::io::join(recv, send)),
+                quic: Some(connection),
+            })
+        })
+    }
+}
+
+pub fn server_endpoint(quic: &ServerQuicConfig, tls: &ServerTlsConfig) -> anyhow::Result<Endpoint> {
+    let mut tls_config = tls::server_config(tls)?;
+    tls_config.alpn_protocols = vec![ALPN.to_vec()];
+    let crypto = QuicServerConfig::try_from(tls_config)
+        .map_err(|e| anyhow::anyhow!("Invalid QUIC TLS configuration: {}", e))?;
+
+    let mut config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    config.transport_config(Arc::new(transport_config(quic.idle_timeout_ms)?));
+
+    let addr = quic.listen_addr.parse().map_err(|e| {
+        anyhow::anyhow!("Invalid QUIC listen address {}: {}", quic.listen_addr, e)
+    })?;
+    Ok(Endpoint::server(config, addr)?)
+}
+
+fn transport_config(idle_timeout_ms: u64) -> anyhow::Result<TransportConfig> {
+    let idle_timeout = IdleTimeout::try_from(Duration::from_millis(idle_timeout_ms))
+        .map_err(|_| anyhow::anyhow!("QUIC idle timeout too large: {} ms", idle_timeout_ms))?;
+    let mut transport = TransportConfig::default();
+    transport
+        .max_idle_timeout(Some(idle_timeout))
+        .max_concurrent_bidi_streams(VarInt::from_u32(MAX_STREAMS))
+        .max_concurrent_uni_streams(VarInt::from_u32(0));
+    Ok(transport)
+}
+
+// Server side of `QuicTransport::connect`.
+impl Listener for Endpoint {
+    fn accept(&mut self) -> BoxFuture<'_, anyhow::Result<Accepted>> {
+        Box::pin(async move {
+            let incoming = Endpoint::accept(self)
+                .await
+                .ok_or_else(|| anyhow::anyhow!("QUIC endpoint closed"))?;
+            let peer = format!("{} (QUIC)", incoming.remote_address());
+            let link: BoxFuture<'static, _> = Box::pin(async move {
+                let connection = incoming.await?;
+                let (send, recv) = connection.accept_bi().await?;
+                Ok(Some(Link {
+                    stream: Box::new(tokio::io::join(recv, send)),
+                    quic: Some(connection),
+                }))
+            });
+            Ok(Accepted { peer, link })
+        })
+    }
+}
+
+// Opens the stream for a connection this side opens; `frame` is its
+// NewConnection frame.
+pub async fn open_stream(connection: &Connection, frame: &Frame) -> anyhow::Result<BoxedStream> {
+    let (send, recv) = connection.open_bi().await?;
+    let mut stream = tokio::io::join(recv, send);
+    protocol::write_plain_frame(&mut stream, frame).await?;
+    Ok(Box::new(stream))
+}
+
+// Streams the peer opens for its connections, each with its NewConnection
+// frame. Without a QUIC connection the channel is closed from the start.
+pub fn accept_streams(connection: Option<Connection>) -> mpsc::Receiver<(Frame, BoxedStream)> {
+    let (incoming_tx, incoming_rx) = mpsc::channel(64);
+    let Some(connection) = connection else {
+        return incoming_rx;
+    };
+
+    let task = async move {
+        loop {
+            let accepted = tokio::select! {
+                accepted = connection.accept_bi() => accepted,
+                // The session is over.
+                _ = incoming_tx.closed() => break,
+            };
+            let Ok((send, recv)) = accepted else {
+                break;
+            };
+            let incoming_tx = incoming_tx.clone();
+            let read_header = async move {
+                let mut stream = tokio::io::join(recv, send);
+                let header = protocol::read_plain_frame(&mut stream);
+                let frame = match tokio::time::timeout(HEADER_TIMEOUT, header).await {
+                    Ok(Ok(frame)) if matches!(frame.frame_type, FrameType::NewConnection) => frame,
+                    Ok(Ok(_)) => {
+                        warn!("Unexpected header on QUIC stream");
+                        return;
+                    }
+                    Ok(Err(e)) => {
+                        warn!("Invalid QUIC stream header: {}", e);
+                        return;
+                    }
+                    Err(_) => {
+                        warn!("Timed out waiting for QUIC stream header");
+                        return;
+                    }
+                };
+                let stream: BoxedStream = Box::new(stream);
+                let _ = incoming_tx.send((frame, stream)).await;
+            };
+            tokio::spawn(read_header.in_current_span());
+        }
+    };
+    tokio::spawn(task.in_current_span());
+    incoming_rx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // A self-signed certificate for "localhost", written out as the PEM
+    // files the configs point at.
+    fn write_cert(dir: &std::path::Path) -> (String, String) {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        std::fs::create_dir_all(dir).unwrap();
+        let cert_file = dir.join("cert.pem");
+        let key_file = dir.join("key.pem");
+        std::fs::write(&cert_file, certified.cert.pem()).unwrap();
+        std::fs::write(&key_file, certified.key_pair.serialize_pem()).unwrap();
+        let path = |p: std::path::PathBuf| p.to_str().unwrap().to_string();
+        (path(cert_file), path(key_file))
+    }
+
+    #[tokio::test]
+    async fn loopback_session_with_a_tunneled_stream() {
+        let dir = std::env::temp_dir().join(format!("kproxy-quic-test-{}", std::process::id()));
+        let (cert_file, key_file) = write_cert(&dir);
+
+        let server_quic = ServerQuicConfig {
+            listen_addr: "127.0.0.1:0".to_string(),
+            idle_timeout_ms: 5000,
+        };
+        let server_tls = ServerTlsConfig {
+            cert_file: cert_file.clone(),
+            key_file,
+            client_ca_file: None,
+        };
+        let mut endpoint = server_endpoint(&server_quic, &server_tls).unwrap();
+        let server_addr = endpoint.local_addr().unwrap().to_string();
+
+        let client_quic = ClientQuicConfig {
+            idle_timeout_ms: 5000,
+            keep_alive_interval_ms: 1000,
+        };
+        let client_tls = ClientTlsConfig {
+            ca_file: Some(cert_file),
+            server_name: Some("localhost".to_string()),
+            ..Default::default()
+        };
+        let transport = QuicTransport::new(&client_quic, Some(&client_tls), &server_addr).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let server = tokio::spawn(async move {
+            let accepted = Listener::accept(&mut endpoint).await.unwrap();
+            assert!(accepted.peer.ends_with("(QUIC)"), "{}", accepted.peer);
+            let mut link = accepted.link.await.unwrap().unwrap();
+
+            // The control stream only shows up once the client writes to it.
+            let mut hello = [0u8; 5];
+            link.stream.read_exact(&mut hello).await.unwrap();
+            assert_eq!(&hello, b"hello");
+            link.stream.write_all(b"world").await.unwrap();
+
+            let mut incoming = accept_streams(link.quic.clone());
+            let (frame, mut stream) = incoming.recv().await.unwrap();
+            assert_eq!(frame.conn_id, 7);
+            assert_eq!(frame.data, b"target:80");
+            let mut data = Vec::new();
+            stream.read_to_end(&mut data).await.unwrap();
+            stream.write_all(&data).await.unwrap();
+            stream.shutdown().await.unwrap();
+            // Keeps the connection open until the client has read the echo.
+            let mut rest = Vec::new();
+            let _ = link.stream.read_to_end(&mut rest).await;
+        });
+
+        let mut link = transport.connect().await.unwrap();
+        link.stream.write_all(b"hello").await.unwrap();
+        let mut world = [0u8; 5];
+        link.stream.read_exact(&mut world).await.unwrap();
+        assert_eq!(&world, b"world");
+
+        let connection = link.quic.clone().unwrap();
+        let frame = Frame {
+            frame_type: FrameType::NewConnection,
+            conn_id: 7,
+            data: b"target:80".to_vec(),
+        };
+        let mut stream = open_stream(&connection, &frame).await.unwrap();
+        stream.write_all(b"tunneled").await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut echoed = Vec::new();
+        stream.read_to_end(&mut echoed).await.unwrap();
+        assert_eq!(echoed, b"tunneled");
+
+        link.stream.shutdown().await.unwrap();
+        server.await.unwrap();
+    }
+}
@@ -0,0 +1,146 @@
+#[cfg(unix)]
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{error, info};
+
+#[cfg(unix)]
+use crate::transport;
+#[cfg(unix)]
+use crate::util;
+
+// Config reloads are asked for with SIGHUP or a "reload" line on the admin
+// socket, and carried out by `run`, which re-reads the config file and applies
+// what can be applied without a restart. The admin socket gets a one-line
+// reply: "ok: " and what changed, or "error: " and why nothing did.
+pub struct Request {
+    reply: Option<oneshot::Sender<String>>,
+}
+
+impl Request {
+    // Logs the outcome and replies to the admin command, if it came from one.
+    pub fn finish(self, result: anyhow::Result<String>) {
+        let reply = match result {
+            Ok(changes) => {
+                info!("Reloaded config: {}", changes);
+                format!("ok: {}", changes)
+            }
+            Err(e) => {
+                error!("Config reload failed: {}", e);
+                format!("error: {}", e)
+            }
+        };
+        if let Some(reply_tx) = self.reply {
+            // Errors such as TOML parse errors span lines; the reply may not.
+            let lines: Vec<&str> = reply.lines().map(str::trim).filter(|l| !l.is_empty()).collect();
+            let _ = reply_tx.send(lines.join(" "));
+        }
+    }
+}
+
+pub fn listen(admin_socket: Option<&str>) -> anyhow::Result<mpsc::Receiver<Request>> {
+    let (tx, rx) = mpsc::channel(4);
+
+    #[cfg(unix)]
+    {
+        let mut hangup = signal(SignalKind::hangup())?;
+        let signal_tx = tx.clone();
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                info!("Received SIGHUP, reloading config");
+                if signal_tx.send(Request { reply: None }).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        if let Some(path) = admin_socket {
+            use std::os::unix::fs::PermissionsExt;
+
+            let listener = transport::bind_unix(path)?;
+            // Only the user the process runs as may ask for reloads.
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+                .map_err(|e| anyhow::anyhow!("Failed to restrict {}: {}", path, e))?;
+            info!("Admin socket listening on {}", path);
+            tokio::spawn(admin_accept_loop(listener, tx));
+        }
+    }
+    #[cfg(not(unix))]
+    if admin_socket.is_some() {
+        return Err(anyhow::anyhow!(
+            "The admin socket is not supported on this platform"
+        ));
+    }
+
+    Ok(rx)
+}
+
+#[cfg(unix)]
+async fn admin_accept_loop(listener: UnixListener, tx: mpsc::Sender<Request>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                util::accept_failed("admin socket", e).await;
+                continue;
+            }
+        };
+        tokio::spawn(handle_admin(stream, tx.clone()));
+    }
+}
+
+#[cfg(unix)]
+async fn handle_admin(stream: UnixStream, tx: mpsc::Sender<Request>) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let reply = match line.trim() {
+            "reload" => {
+                let (reply_tx, reply_rx) = oneshot::channel();
+                let request = Request {
+                    reply: Some(reply_tx),
+                };
+                match tx.send(request).await {
+                    Ok(()) => reply_rx.await.unwrap_or_else(|_| "error: no reply".to_string()),
+                    Err(_) => "error: shutting down".to_string(),
+                }
+            }
+            "" => continue,
+            command => format!("error: unknown command {:?}", command),
+        };
+        if writer.write_all(format!("{}\n", reply).as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+// The `reload` subcommand: asks a running server or client to reload its
+// config through its admin socket and prints the reply.
+#[cfg(unix)]
+pub async fn send(path: &str) -> anyhow::Result<()> {
+    let stream = UnixStream::connect(path)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to connect to {}: {}", path, e))?;
+    let (reader, mut writer) = stream.into_split();
+    writer.write_all(b"reload\n").await?;
+    let reply = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("No reply from {}", path))?;
+    println!("{}", reply);
+    if reply.starts_with("error") {
+        return Err(anyhow::anyhow!("Reload failed"));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub async fn send(_path: &str) -> anyhow::Result<()> {
+    Err(anyhow::anyhow!(
+        "The admin socket is not supported on this platform"
+    ))
+}
@@ -1,260 +1,887 @@
 use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{mpsc, Mutex};
-use tracing::{error, info, warn};
+use futures_util::stream::{FuturesOrdered, StreamExt};
+use tokio::net::{lookup_host, TcpListener, TcpStream, UdpSocket};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::watch;
+use tokio::task::{AbortHandle, JoinSet};
+use tokio::time::Instant;
+use tracing::{error, field, info, info_span, warn, Instrument, Span};
 
-use crate::crypto;
-use crate::protocol::{self, Frame, FrameType};
+use crate::acl::Acl;
+use crate::config::{self, HeartbeatConfig, ServerConfig};
+use crate::handshake;
+use crate::heartbeat::{self, Heartbeat};
+use crate::protocol::{self, CloseReason, Frame, FrameSender, FrameType};
+use crate::quic;
+use crate::reload;
+use crate::shutdown::{self, Shutdown};
+use crate::transport::{self, BoxFuture, Link, Listener};
+use crate::tunnel::{self, ConnIds, Connections};
+use crate::udp;
+use crate::users::{self, QuotaGuard, User};
+use crate::util;
 
-pub async fn run(config: &crate::config::ServerConfig) -> anyhow::Result<()> {
-    let listener = TcpListener::bind(&config.listen_addr).await?;
-    info!("Server listening on {}", config.listen_addr);
-
-    let key = crypto::derive_key(&config.token);
-    let expected_token = config.token.clone();
-
-    loop {
-        let (stream, addr) = listener.accept().await?;
-        info!("New connection from {}", addr);
+struct ServerState {
+    users: Vec<Arc<User>>,
+    psks: Vec<[u8; 32]>,
+    connect_timeout: Duration,
+    handshake_timeout: Duration,
+    acl: Acl,
+    allow_reverse_forwards: bool,
+    bind_acl: Acl,
+    udp_idle_timeout: Duration,
+    heartbeat: HeartbeatConfig,
+    shutdown_grace: Duration,
+}
 
-        let token = expected_token.clone();
+impl ServerState {
+    // `previous_users` are the users before a reload, see `users::load_users`.
+    fn new(config: &ServerConfig, previous_users: &[Arc<User>]) -> anyhow::Result<Self> {
+        let users = users::load_users(config, previous_users)?;
+        Ok(ServerState {
+            psks: users.iter().map(|u| u.psk).collect(),
+            users,
+            connect_timeout: Duration::from_millis(config.connect_timeout_ms),
+            handshake_timeout: Duration::from_millis(config.handshake_timeout_ms),
+            acl: Acl::from_config(&config.acl)?,
+            allow_reverse_forwards: config.allow_reverse_forwards,
+            bind_acl: Acl::from_config(&config.bind_acl)?,
+            udp_idle_timeout: Duration::from_millis(config.udp_idle_timeout_ms),
+            heartbeat: config.heartbeat.clone(),
+            shutdown_grace: Duration::from_millis(config.shutdown_grace_ms),
+        })
+    }
 
-        tokio::spawn(async move {
-            if let Err(e) = handle_client(stream, key, &token).await {
-                error!("Client handler error: {}", e);
-            }
-        });
+    // The current version of a user from an earlier state, unless a reload
+    // removed it or changed its token.
+    fn find_user(&self, user: &User) -> Option<Arc<User>> {
+        self.users
+            .iter()
+            .find(|u| u.name == user.name && u.psk == user.psk)
+            .cloned()
     }
 }
 
-async fn handle_client(
-    stream: TcpStream,
-    key: [u8; 32],
-    expected_token: &str,
-) -> anyhow::Result<()> {
-    stream.set_nodelay(true)?;
-    let (mut reader, writer) = tokio::io::split(stream);
-    let writer = Arc::new(Mutex::new(writer));
+// A forward the session is about to add, once the registrations ahead of it
+// have been answered.
+enum Registration {
+    Forward {
+        remote_addr: String,
+        slot: QuotaGuard,
+    },
+    Reverse {
+        bind_addr: String,
+        listener: TcpListener,
+        slot: QuotaGuard,
+    },
+}
+
+// Datagrams queued per UDP flow before further ones are dropped.
+const UDP_FLOW_QUEUE: usize = 256;
+
+pub async fn run(config: ServerConfig, path: &str) -> anyhow::Result<()> {
+    let (state_tx, state_rx) = watch::channel(Arc::new(ServerState::new(&config, &[])?));
+    let mut reloads = reload::listen(config.admin_socket.as_deref())?;
 
-    let frame = protocol::read_frame(&mut reader, &key).await?;
-    if !matches!(frame.frame_type, FrameType::Auth) {
-        return Err(anyhow::anyhow!("Expected Auth frame"));
+    let (mut trigger, shutdown) = shutdown::listen()?;
+    let mut accept_loops = JoinSet::new();
+    for listener in transport::server_listeners(&config).await? {
+        accept_loops.spawn(accept_loop(listener, state_rx.clone(), shutdown.clone()));
     }
+    // Sessions hold the remaining handles; the shutdown is done once they end.
+    drop(shutdown);
 
-    let token = String::from_utf8(frame.data)?;
-    if token != expected_token {
-        let response = Frame {
-            frame_type: FrameType::AuthResult,
-            conn_id: 0,
-            data: b"auth failed".to_vec(),
-        };
-        let mut w = writer.lock().await;
-        protocol::write_frame(&mut *w, &key, &response).await?;
-        return Err(anyhow::anyhow!("Authentication failed"));
+    // Accept loops only end on error.
+    loop {
+        tokio::select! {
+            () = trigger.signaled() => break,
+            Some(result) = accept_loops.join_next() => result??,
+            Some(request) = reloads.recv() => request.finish(reload(path, &config, &state_tx)),
+        }
     }
 
-    info!("Client authenticated");
+    // Closes the listeners.
+    accept_loops.shutdown().await;
+    let force_closed = trigger.drained().await;
+    info!("Shutdown complete, {} connections force-closed", force_closed);
+    Ok(())
+}
 
-    let response = Frame {
-        frame_type: FrameType::AuthResult,
-        conn_id: 0,
-        data: b"ok".to_vec(),
-    };
+// Re-reads the config for sessions to pick up: users and ACLs apply to their
+// next connection or forward, and sessions whose token is gone end. The
+// settings of the listeners stay as `running` until a restart.
+fn reload(
+    path: &str,
+    running: &ServerConfig,
+    state: &watch::Sender<Arc<ServerState>>,
+) -> anyhow::Result<String> {
+    let config = config::load_server_config(path)?;
+    let old_users = state.borrow().users.clone();
+    let new_state = ServerState::new(&config, &old_users)?;
+
+    if config.listen_addr != running.listen_addr
+        || config.admin_socket != running.admin_socket
+        || config.tls != running.tls
+        || config.websocket != running.websocket
+        || config.quic != running.quic
     {
-        let mut w = writer.lock().await;
-        protocol::write_frame(&mut *w, &key, &response).await?;
+        warn!("Changes to listen_addr, admin_socket, [tls], [websocket] and [quic] need a restart");
     }
 
-    let (writer_tx, mut writer_rx) = mpsc::channel::<Vec<u8>>(4096);
+    let revoked = old_users
+        .iter()
+        .filter(|user| new_state.find_user(user).is_none())
+        .count();
+    let changes = format!("{} users ({} revoked)", new_state.users.len(), revoked);
+    state.send_replace(Arc::new(new_state));
+    Ok(changes)
+}
 
-    let writer_clone = writer.clone();
-    let writer_handle = tokio::spawn(async move {
-        while let Some(raw_frame) = writer_rx.recv().await {
-            let mut w = writer_clone.lock().await;
-            if let Err(e) = w.write_all(&raw_frame).await {
-                error!("Control write error: {}", e);
-                break;
-            }
-            if let Err(e) = w.flush().await {
-                error!("Control flush error: {}", e);
-                break;
+async fn accept_loop(
+    mut listener: Box<dyn Listener>,
+    state: watch::Receiver<Arc<ServerState>>,
+    shutdown: Shutdown,
+) -> anyhow::Result<()> {
+    loop {
+        let accepted = listener.accept().await?;
+        info!("New connection from {}", accepted.peer);
+
+        let state = state.clone();
+        let shutdown = shutdown.clone();
+        let span = info_span!("session", peer = %accepted.peer, user = field::Empty);
+
+        tokio::spawn(
+            async move {
+                // The link and the handshake together get `handshake_timeout`,
+                // so a client that connects and never speaks is dropped.
+                let deadline = Instant::now() + state.borrow().handshake_timeout;
+                // A client still setting up its link when the shutdown starts
+                // is dropped.
+                let link = tokio::select! {
+                    link = tokio::time::timeout_at(deadline, accepted.link) => {
+                        link.unwrap_or_else(|_| Err(anyhow::anyhow!("Timed out setting up link")))
+                    }
+                    () = shutdown.started() => return,
+                };
+                let result = match link {
+                    Ok(Some(link)) => run_session(link, deadline, state, shutdown).await,
+                    Ok(None) => Ok(()),
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    error!("Client handler error: {}", e);
+                }
             }
+            .instrument(span),
+        );
+    }
+}
+
+async fn run_session(
+    link: Link,
+    handshake_deadline: Instant,
+    mut state_rx: watch::Receiver<Arc<ServerState>>,
+    shutdown: Shutdown,
+) -> anyhow::Result<()> {
+    // Replaced on a reload, for everything after it.
+    let mut state = state_rx.borrow_and_update().clone();
+    let Link { stream, quic } = link;
+    let (mut reader, mut writer) = tokio::io::split(stream);
+
+    let handshake = handshake::server_handshake(&mut reader, &mut writer, &state.psks);
+    let (ciphers, user_index, negotiated) = tokio::select! {
+        result = tokio::time::timeout_at(handshake_deadline, handshake) => {
+            result.map_err(|_| anyhow::anyhow!("Timed out waiting for the handshake"))??
         }
-    });
+        () = shutdown.started() => return Ok(()),
+    };
+    let mut user = state.users[user_index].clone();
+    Span::current().record("user", user.name.as_str());
+
+    info!(
+        "Client authenticated (protocol version {}, capabilities 0x{:x})",
+        negotiated.version, negotiated.capabilities
+    );
+
+    let (mut frames, reader_handle) = tunnel::spawn_reader(reader, ciphers.recv);
+    let (writer_tx, writer_rx) = protocol::frame_channel();
+    let writer_handle = tunnel::spawn_writer(writer, ciphers.send, writer_rx);
 
-    let connections: Arc<Mutex<HashMap<u32, tokio::io::WriteHalf<TcpStream>>>> =
-        Arc::new(Mutex::new(HashMap::new()));
-    let mut forward_map: HashMap<u32, String> = HashMap::new();
+    let mut incoming = quic::accept_streams(quic.clone());
+    let half_close = negotiated.supports(protocol::CAP_HALF_CLOSE);
+    let connections = Arc::new(Connections::new(quic, half_close));
+    // Each registered forward holds one of the user's forward slots until the
+    // session ends.
+    let mut forward_map: HashMap<u32, (String, QuotaGuard)> = HashMap::new();
     let mut forward_id_counter: u32 = 0;
+    // Registrations in the order the client asked for them, which is the
+    // order it expects the results in; a reverse forward may still be
+    // binding its listener.
+    let mut registrations: FuturesOrdered<BoxFuture<'static, Result<Registration, String>>> =
+        FuturesOrdered::new();
+    // Accept loops for reverse forwards; dropping the set when the session
+    // ends closes their listeners.
+    let mut reverse_listeners = JoinSet::new();
+    let mut reverse_forwards: HashMap<u32, AbortHandle> = HashMap::new();
+    let conn_ids = Arc::new(ConnIds::server());
+    // Each UDP flow's task owns its socket; dropping the sender ends it.
+    // A task that ends by itself returns its flow id so the entry goes too.
+    let mut udp_flows: HashMap<u32, mpsc::Sender<Vec<u8>>> = HashMap::new();
+    let mut udp_tasks = JoinSet::new();
+    let mut heartbeat = Heartbeat::new(&state.heartbeat, &negotiated);
+    // Set once the shutdown has started: no new connections are accepted, and
+    // the open ones are force-closed if they are still running by then.
+    let mut drain_deadline: Option<Instant> = None;
 
     loop {
-        let frame = match protocol::read_frame(&mut reader, &key).await {
-            Ok(f) => f,
-            Err(e) => {
-                let msg = e.to_string();
-                if msg.contains("unexpected eof")
-                    || msg.contains("EOF")
-                    || msg.contains("reset")
-                {
-                    info!("Client disconnected");
-                } else {
-                    error!("Read frame error: {}", e);
+        let (frame, stream) = tokio::select! {
+            frame = frames.recv() => match frame {
+                Some(Ok(frame)) => (frame, None),
+                Some(Err(e)) => {
+                    let msg = e.to_string();
+                    if msg.contains("unexpected eof")
+                        || msg.contains("early eof")
+                        || msg.contains("EOF")
+                        || msg.contains("reset")
+                        || msg.contains("closed")
+                        || msg.contains("lost")
+                    {
+                        info!("Client disconnected");
+                    } else {
+                        error!("Read frame error: {}", e);
+                    }
+                    break;
+                }
+                None => break,
+            },
+            Some((frame, stream)) = incoming.recv() => (frame, Some(stream)),
+            result = heartbeat.tick(&writer_tx) => match result {
+                Ok(()) => continue,
+                Err(e) => {
+                    warn!("Client timed out: {}", e);
+                    break;
+                }
+            },
+            Ok(()) = state_rx.changed() => {
+                state = state_rx.borrow_and_update().clone();
+                match state.find_user(&user) {
+                    Some(current) => user = current,
+                    None => {
+                        warn!("Token revoked by a config reload, closing the session");
+                        break;
+                    }
+                }
+                continue;
+            }
+            () = shutdown.started(), if drain_deadline.is_none() => {
+                info!("Draining {} connections", connections.live());
+                let _ = writer_tx.send_control(Frame::go_away());
+                reverse_listeners.abort_all();
+                drain_deadline = Some(Instant::now() + state.shutdown_grace);
+                continue;
+            }
+            Some(registration) = registrations.next() => {
+                let registration = match registration {
+                    Ok(_) if drain_deadline.is_some() => Err("server is shutting down".to_string()),
+                    registration => registration,
+                };
+                let registration = match registration {
+                    Ok(registration) => registration,
+                    Err(reason) => {
+                        send_register_result(&writer_tx, Err(&reason));
+                        continue;
+                    }
+                };
+
+                forward_id_counter += 1;
+                let forward_id = forward_id_counter;
+                match registration {
+                    Registration::Forward { remote_addr, slot } => {
+                        info!("Registered forward {}: -> {}", forward_id, remote_addr);
+                        forward_map.insert(forward_id, (remote_addr, slot));
+                    }
+                    Registration::Reverse { bind_addr, listener, slot } => {
+                        info!("Registered reverse forward {}: {} ->", forward_id, bind_addr);
+                        let accept = reverse_accept_loop(
+                            listener,
+                            forward_id,
+                            slot,
+                            user.clone(),
+                            connections.clone(),
+                            writer_tx.clone(),
+                            conn_ids.clone(),
+                        );
+                        let listener = reverse_listeners.spawn(accept.in_current_span());
+                        reverse_forwards.insert(forward_id, listener);
+                    }
                 }
+                send_register_result(&writer_tx, Ok(forward_id));
+                continue;
+            }
+            Some(Ok(flow_id)) = udp_tasks.join_next() => {
+                // The id may have been reused by a newer flow already.
+                if udp_flows.get(&flow_id).is_some_and(|flow| flow.is_closed()) {
+                    udp_flows.remove(&flow_id);
+                }
+                continue;
+            }
+            () = connections.drained(), if drain_deadline.is_some() => break,
+            () = shutdown.grace_expired(drain_deadline) => {
+                let open = connections.live();
+                let why = if shutdown.is_forced() {
+                    "Shutdown forced"
+                } else {
+                    "Grace period over"
+                };
+                warn!("{}, force-closing {} connections", why, open);
+                shutdown.force_closed(open);
                 break;
             }
         };
 
         match frame.frame_type {
             FrameType::RegisterForward => {
-                let remote_addr = String::from_utf8(frame.data)?;
-                forward_id_counter += 1;
-                let forward_id = forward_id_counter;
-                forward_map.insert(forward_id, remote_addr.clone());
-
-                info!(
-                    "Registered forward {}: -> {}",
-                    forward_id, remote_addr
-                );
+                let Ok(remote_addr) = String::from_utf8(frame.data) else {
+                    warn!("Refused forward: invalid address");
+                    let refusal = Err("invalid address".to_string());
+                    registrations.push_back(Box::pin(std::future::ready(refusal)));
+                    continue;
+                };
 
-                let mut data = vec![0x00];
-                data.extend_from_slice(&forward_id.to_be_bytes());
-                let response = Frame {
-                    frame_type: FrameType::RegisterForwardResult,
-                    conn_id: 0,
-                    data,
+                let check = check_target(&state, &user, &remote_addr).and_then(|()| {
+                    user.forwards
+                        .acquire()
+                        .ok_or_else(|| "forward limit reached".to_string())
+                });
+                let registration = match check {
+                    Ok(slot) => Ok(Registration::Forward { remote_addr, slot }),
+                    Err(reason) => {
+                        warn!("Refused forward -> {}: {}", remote_addr, reason);
+                        Err(reason)
+                    }
                 };
-                let _ = protocol::send_frame(&writer_tx, &key, &response);
+                registrations.push_back(Box::pin(std::future::ready(registration)));
             }
-            FrameType::NewConnection => {
-                if frame.data.len() < 5 {
-                    warn!("Invalid NewConnection frame");
-                    let close_frame = Frame {
-                        frame_type: FrameType::CloseConnection,
-                        conn_id: frame.conn_id,
-                        data: vec![0x01],
-                    };
-                    let _ = protocol::send_frame(&writer_tx, &key, &close_frame);
+            FrameType::RegisterReverseForward => {
+                let Ok(bind_addr) = String::from_utf8(frame.data) else {
+                    warn!("Refused reverse forward: invalid address");
+                    let refusal = Err("invalid address".to_string());
+                    registrations.push_back(Box::pin(std::future::ready(refusal)));
                     continue;
-                }
-                let forward_id = u32::from_be_bytes([
-                    frame.data[1],
-                    frame.data[2],
-                    frame.data[3],
-                    frame.data[4],
-                ]);
-                let conn_id = frame.conn_id;
+                };
 
-                let remote_addr = match forward_map.get(&forward_id) {
-                    Some(addr) => addr.clone(),
-                    None => {
-                        warn!("Unknown forward id: {}", forward_id);
-                        let close_frame = Frame {
-                            frame_type: FrameType::CloseConnection,
-                            conn_id,
-                            data: vec![0x01],
-                        };
-                        let _ = protocol::send_frame(&writer_tx, &key, &close_frame);
+                let check = if !state.allow_reverse_forwards {
+                    Err("reverse forwarding is disabled".to_string())
+                } else {
+                    check_bind(&state, &user, &bind_addr).and_then(|()| {
+                        user.forwards
+                            .acquire()
+                            .ok_or_else(|| "forward limit reached".to_string())
+                    })
+                };
+                let slot = match check {
+                    Ok(slot) => slot,
+                    Err(reason) => {
+                        warn!("Refused reverse forward {}: {}", bind_addr, reason);
+                        registrations.push_back(Box::pin(std::future::ready(Err(reason))));
                         continue;
                     }
                 };
 
-                let remote_stream = match TcpStream::connect(&remote_addr).await {
-                    Ok(s) => s,
-                    Err(e) => {
-                        warn!("Failed to connect to {}: {}", remote_addr, e);
-                        let close_frame = Frame {
-                            frame_type: FrameType::CloseConnection,
-                            conn_id,
-                            data: vec![0x01],
-                        };
-                        let _ = protocol::send_frame(&writer_tx, &key, &close_frame);
-                        continue;
+                // Binding may mean a DNS lookup, which mustn't hold up the
+                // frames of the session's connections.
+                let bind = bind_reverse(bind_addr.clone(), slot, state.clone(), user.clone());
+                let bind = tokio::spawn(bind.in_current_span());
+                registrations.push_back(Box::pin(
+                    async move {
+                        let bound = bind.await.unwrap_or_else(|e| Err(e.to_string()));
+                        if let Err(reason) = &bound {
+                            warn!("Refused reverse forward {}: {}", bind_addr, reason);
+                        }
+                        bound
                     }
-                };
+                    .in_current_span(),
+                ));
+            }
+            FrameType::NewConnection => {
+                let conn_id = frame.conn_id;
 
-                remote_stream.set_nodelay(true)?;
-                info!(
-                    "Connected to {} for connection {}",
-                    remote_addr, conn_id
-                );
+                // Ids from SERVER_CONN_ID_BASE up are the server's own.
+                if conn_id >= tunnel::SERVER_CONN_ID_BASE {
+                    warn!("Refused connection {}: id in the server's range", conn_id);
+                    let reason = CloseReason::ProtocolError;
+                    tunnel::send_close(&writer_tx, conn_id, reason, "connection id out of range");
+                    continue;
+                }
 
-                let (read_half, write_half) = tokio::io::split(remote_stream);
-                {
-                    let mut conns = connections.lock().await;
-                    conns.insert(conn_id, write_half);
+                if drain_deadline.is_some() {
+                    let reason = CloseReason::Shutdown;
+                    tunnel::send_close(&writer_tx, conn_id, reason, "server is shutting down");
+                    continue;
                 }
 
+                let target = connection_target(&frame.data, &forward_map, &state, &user);
+                let remote_addr = match target {
+                    Ok(target) => target,
+                    Err((reason, message)) => {
+                        warn!("Refused connection {}: {}", conn_id, message);
+                        tunnel::send_close(&writer_tx, conn_id, reason, &message);
+                        continue;
+                    }
+                };
+
+                let Some(conn_slot) = user.connections.acquire() else {
+                    warn!("Connection limit reached, refusing connection {}", conn_id);
+                    let reason = CloseReason::LimitExceeded;
+                    tunnel::send_close(&writer_tx, conn_id, reason, "connection limit reached");
+                    continue;
+                };
+
+                let Some(pending) = connections.accept(conn_id, &writer_tx, stream) else {
+                    continue;
+                };
                 let tx = writer_tx.clone();
                 let conns = connections.clone();
-                let r_key = key;
-
-                tokio::spawn(async move {
-                    let mut reader = read_half;
-                    let mut buf = vec![0u8; 32768];
-                    loop {
-                        match reader.read(&mut buf).await {
-                            Ok(0) => break,
-                            Ok(n) => {
-                                let frame = Frame {
-                                    frame_type: FrameType::Data,
-                                    conn_id,
-                                    data: buf[..n].to_vec(),
-                                };
-                                if protocol::send_frame(&tx, &r_key, &frame).is_err() {
-                                    break;
-                                }
-                            }
-                            Err(_) => break,
+                let state = state.clone();
+                let user = user.clone();
+
+                let task = async move {
+                    let _conn_slot = conn_slot;
+                    let acls = [&state.acl, &user.acl];
+                    let dial = dial(&remote_addr, &acls);
+                    let timeout = state.connect_timeout;
+                    let remote_stream = match tokio::time::timeout(timeout, dial).await {
+                        Ok(Ok(s)) => s,
+                        Ok(Err((reason, message))) => {
+                            warn!("Failed to connect to {}: {}", remote_addr, message);
+                            conns.remove(conn_id);
+                            tunnel::send_close(&tx, conn_id, reason, &message);
+                            return;
                         }
-                    }
+                        Err(_) => {
+                            warn!("Timed out connecting to {}", remote_addr);
+                            conns.remove(conn_id);
+                            let reason = CloseReason::Timeout;
+                            tunnel::send_close(&tx, conn_id, reason, "connect timed out");
+                            return;
+                        }
+                    };
 
-                    {
-                        let mut c = conns.lock().await;
-                        c.remove(&conn_id);
-                    }
-                    info!("Connection {} closed (remote read ended)", conn_id);
-                    let close_frame = Frame {
-                        frame_type: FrameType::CloseConnection,
+                    let _ = remote_stream.set_nodelay(true);
+                    info!(
+                        "Connected to {} for connection {}",
+                        remote_addr, conn_id
+                    );
+                    let ready = Frame {
+                        frame_type: FrameType::NewConnectionResult,
                         conn_id,
-                        data: vec![],
+                        data: vec![0x00],
                     };
-                    let _ = protocol::send_frame(&tx, &r_key, &close_frame);
-                });
+                    let _ = tx.send_control(ready);
+
+                    let reason = pending.run(remote_stream).await;
+                    conns.finish(&tx, conn_id, reason).await;
+                };
+                tokio::spawn(task.in_current_span());
             }
-            FrameType::Data => {
-                let conn_id = frame.conn_id;
-                let mut conns = connections.lock().await;
-                if let Some(write_half) = conns.get_mut(&conn_id) {
-                    if let Err(e) = write_half.write_all(&frame.data).await {
-                        warn!("Write to connection {} error: {}", conn_id, e);
-                        conns.remove(&conn_id);
-                        drop(conns);
-                        info!("Connection {} closed (write error)", conn_id);
-                        let close_frame = Frame {
-                            frame_type: FrameType::CloseConnection,
-                            conn_id,
-                            data: vec![],
-                        };
-                        let _ = protocol::send_frame(&writer_tx, &key, &close_frame);
+            FrameType::NewUdpFlow => {
+                let flow_id = frame.conn_id;
+
+                if drain_deadline.is_some() {
+                    let reason = CloseReason::Shutdown;
+                    tunnel::send_close(&writer_tx, flow_id, reason, "server is shutting down");
+                    continue;
+                }
+
+                let target = connection_target(&frame.data, &forward_map, &state, &user);
+                let target = match target {
+                    Ok(target) => target,
+                    Err((reason, message)) => {
+                        warn!("Refused UDP flow {}: {}", flow_id, message);
+                        tunnel::send_close(&writer_tx, flow_id, reason, &message);
+                        continue;
+                    }
+                };
+
+                let Some(flow_slot) = user.connections.acquire() else {
+                    warn!("Connection limit reached, refusing UDP flow {}", flow_id);
+                    let reason = CloseReason::LimitExceeded;
+                    tunnel::send_close(&writer_tx, flow_id, reason, "connection limit reached");
+                    continue;
+                };
+
+                // Datagrams that arrive while the target is being resolved
+                // wait in the channel.
+                let (datagram_tx, datagram_rx) = mpsc::channel(UDP_FLOW_QUEUE);
+                udp_flows.insert(flow_id, datagram_tx);
+
+                let flow = run_udp_flow(
+                    flow_id,
+                    target,
+                    datagram_rx,
+                    flow_slot,
+                    state.clone(),
+                    user.clone(),
+                    writer_tx.clone(),
+                );
+                udp_tasks.spawn(
+                    async move {
+                        flow.await;
+                        flow_id
                     }
+                    .in_current_span(),
+                );
+            }
+            FrameType::UdpDatagram => {
+                let Some(datagram_tx) = udp_flows.get(&frame.conn_id) else {
+                    continue;
+                };
+                if let Err(TrySendError::Closed(_)) = datagram_tx.try_send(frame.data) {
+                    udp_flows.remove(&frame.conn_id);
                 }
             }
+            FrameType::Data => {
+                connections.route_data(&writer_tx, frame);
+            }
+            FrameType::WindowUpdate => {
+                connections.handle_window_update(&writer_tx, frame);
+            }
+            FrameType::Fin => {
+                connections.handle_fin(frame.conn_id);
+            }
             FrameType::CloseConnection => {
-                info!("Connection {} closed by client", frame.conn_id);
-                let mut conns = connections.lock().await;
-                conns.remove(&frame.conn_id);
+                match tunnel::received_close(&frame) {
+                    (CloseReason::Normal, _) => {
+                        info!("Connection {} closed by client", frame.conn_id);
+                    }
+                    (reason, message) => warn!(
+                        "Connection {} closed by client: {}",
+                        frame.conn_id,
+                        reason.describe(&message)
+                    ),
+                }
+                connections.remove(frame.conn_id);
+                udp_flows.remove(&frame.conn_id);
+            }
+            FrameType::UnregisterForward => {
+                let Some(forward_id) = frame
+                    .data
+                    .get(..4)
+                    .map(|id| u32::from_be_bytes([id[0], id[1], id[2], id[3]]))
+                else {
+                    warn!("Invalid UnregisterForward frame");
+                    continue;
+                };
+                if forward_map.remove(&forward_id).is_some() {
+                    info!("Unregistered forward {}", forward_id);
+                } else if let Some(listener) = reverse_forwards.remove(&forward_id) {
+                    listener.abort();
+                    info!("Unregistered reverse forward {}", forward_id);
+                }
             }
+            FrameType::GoAway => {
+                info!("Client is shutting down, closing its reverse forwards");
+                reverse_listeners.abort_all();
+            }
+            FrameType::Ping => heartbeat::send_pong(&writer_tx, frame),
+            FrameType::Pong => heartbeat.handle_pong(&frame),
             _ => {
                 warn!("Unexpected frame type: 0x{:02x}", frame.frame_type as u8);
             }
         }
     }
 
+    if drain_deadline.is_some() {
+        // UDP flows only end when idle, so they aren't waited for.
+        let flows = udp_flows.values().filter(|flow| !flow.is_closed()).count();
+        if flows > 0 {
+            info!("Force-closing {} UDP flows", flows);
+            shutdown.force_closed(flows);
+        }
+    }
+    reverse_listeners.abort_all();
+    connections.clear();
     drop(writer_tx);
+    reader_handle.abort();
     writer_handle.abort();
+    info!("Connection closes since startup: {}", tunnel::close_stats());
 
     Ok(())
 }
+
+// Works out the target of a NewConnection or NewUdpFlow frame: 0x00 + forward
+// id for a registered forward, 0x01 + host:port for a dynamic target chosen by
+// the client. On error returns the close reason and message to answer with.
+fn connection_target(
+    data: &[u8],
+    forward_map: &HashMap<u32, (String, QuotaGuard)>,
+    state: &ServerState,
+    user: &User,
+) -> Result<String, (CloseReason, String)> {
+    match data.first() {
+        Some(0x00) if data.len() >= 5 => {
+            let forward_id = u32::from_be_bytes([data[1], data[2], data[3], data[4]]);
+            match forward_map.get(&forward_id) {
+                // Checked again in case a reload changed the ACLs since the
+                // forward was registered.
+                Some((addr, _)) => check_target(state, user, addr)
+                    .map(|()| addr.clone())
+                    .map_err(|message| (CloseReason::AclDenied, message)),
+                None => Err((
+                    CloseReason::ConnectFailed,
+                    format!("unknown forward id {}", forward_id),
+                )),
+            }
+        }
+        Some(0x01) => {
+            let target = String::from_utf8_lossy(&data[1..]).into_owned();
+            check_target(state, user, &target)
+                .map(|()| target)
+                .map_err(|message| (CloseReason::AclDenied, message))
+        }
+        _ => Err((CloseReason::ProtocolError, "invalid target".to_string())),
+    }
+}
+
+// Checks a target as written by the client against the global and the user's
+// ACL, before it is resolved.
+fn check_target(state: &ServerState, user: &User, remote_addr: &str) -> Result<(), String> {
+    let (host, port) = config::parse_host_port(remote_addr).map_err(|e| e.to_string())?;
+    state.acl.check_target(&host, port)?;
+    user.acl.check_target(&host, port)
+}
+
+// Whether the user may have a reverse forward listen on `bind_addr`.
+fn check_bind(state: &ServerState, user: &User, bind_addr: &str) -> Result<(), String> {
+    let (host, port) = config::parse_host_port(bind_addr).map_err(|e| e.to_string())?;
+    state.bind_acl.check_target(&host, port)?;
+    user.bind_acl.check_target(&host, port)
+}
+
+// Binds a reverse forward's listener. The address it ended up on has to pass
+// the bind ACLs as well, for a name or port 0 in `bind_addr`.
+async fn bind_reverse(
+    bind_addr: String,
+    slot: QuotaGuard,
+    state: Arc<ServerState>,
+    user: Arc<User>,
+) -> Result<Registration, String> {
+    let listener = TcpListener::bind(&bind_addr)
+        .await
+        .map_err(|e| format!("failed to bind {}: {}", bind_addr, e))?;
+    let bound = listener.local_addr().map_err(|e| e.to_string())?;
+    let (host, _) = config::parse_host_port(&bind_addr).map_err(|e| e.to_string())?;
+    state.bind_acl.check_resolved(&host, bound.ip(), bound.port())?;
+    user.bind_acl.check_resolved(&host, bound.ip(), bound.port())?;
+    Ok(Registration::Reverse {
+        bind_addr,
+        listener,
+        slot,
+    })
+}
+
+// Resolves the target and connects to the first address the ACL permits, so a
+// name that resolves into a denied range is refused even though it passed the
+// check at registration.
+async fn dial(remote_addr: &str, acls: &[&Acl]) -> Result<TcpStream, (CloseReason, String)> {
+    let mut last_err = None;
+    for addr in resolve(remote_addr, acls).await? {
+        match TcpStream::connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    let e = last_err.expect("resolve returns at least one address");
+    Err((tunnel::io_close_reason(&e), e.to_string()))
+}
+
+// The target's addresses that the ACLs permit, in resolver order.
+async fn resolve(
+    remote_addr: &str,
+    acls: &[&Acl],
+) -> Result<Vec<SocketAddr>, (CloseReason, String)> {
+    let (host, port) = config::parse_host_port(remote_addr)
+        .map_err(|e| (CloseReason::ConnectFailed, e.to_string()))?;
+    let addrs: Vec<SocketAddr> = lookup_host(remote_addr)
+        .await
+        .map_err(|e| (CloseReason::DnsFailure, e.to_string()))?
+        .collect();
+    if addrs.is_empty() {
+        let message = format!("No addresses found for {}", remote_addr);
+        return Err((CloseReason::DnsFailure, message));
+    }
+
+    let mut permitted = Vec::with_capacity(addrs.len());
+    let mut denied = None;
+    for addr in addrs {
+        let check = acls
+            .iter()
+            .try_for_each(|acl| acl.check_resolved(&host, addr.ip(), port));
+        match check {
+            Ok(()) => permitted.push(addr),
+            Err(reason) => denied = Some(reason),
+        }
+    }
+    match denied {
+        Some(reason) if permitted.is_empty() => Err((CloseReason::AclDenied, reason)),
+        _ => Ok(permitted),
+    }
+}
+
+// Relays one UDP flow between the client and a socket connected to the
+// target until either side closes it or it has been idle for
+// `udp_idle_timeout`.
+async fn run_udp_flow(
+    flow_id: u32,
+    target: String,
+    mut datagram_rx: mpsc::Receiver<Vec<u8>>,
+    _flow_slot: QuotaGuard,
+    state: Arc<ServerState>,
+    user: Arc<User>,
+    tx: FrameSender,
+) {
+    let acls = [&state.acl, &user.acl];
+    let socket = match tokio::time::timeout(state.connect_timeout, bind_udp(&target, &acls)).await
+    {
+        Ok(Ok(socket)) => socket,
+        Ok(Err((reason, message))) => {
+            warn!("Failed to open UDP flow to {}: {}", target, message);
+            tunnel::send_close(&tx, flow_id, reason, &message);
+            return;
+        }
+        Err(_) => {
+            warn!("Timed out resolving {}", target);
+            tunnel::send_close(&tx, flow_id, CloseReason::Timeout, "connect timed out");
+            return;
+        }
+    };
+    info!("UDP flow {} -> {}", flow_id, target);
+
+    let mut buf = vec![0u8; udp::MAX_DATAGRAM_SIZE];
+    let mut deadline = Instant::now() + state.udp_idle_timeout;
+    loop {
+        tokio::select! {
+            datagram = datagram_rx.recv() => {
+                // Closed by the client.
+                let Some(datagram) = datagram else {
+                    return;
+                };
+                let _ = socket.send(&datagram).await;
+                deadline = Instant::now() + state.udp_idle_timeout;
+            }
+            result = socket.recv(&mut buf) => match result {
+                Ok(n) => {
+                    udp::send_datagram(&tx, flow_id, &buf[..n]);
+                    deadline = Instant::now() + state.udp_idle_timeout;
+                }
+                // An ICMP error from an earlier send; the flow stays usable.
+                Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {}
+                Err(e) => {
+                    warn!("UDP flow {} receive error: {}", flow_id, e);
+                    break;
+                }
+            },
+            _ = tokio::time::sleep_until(deadline) => {
+                info!("UDP flow {} expired", flow_id);
+                break;
+            }
+        }
+    }
+
+    tunnel::send_close(&tx, flow_id, CloseReason::Normal, "");
+}
+
+// Like `dial`, but returns a UDP socket connected to the first permitted
+// address.
+async fn bind_udp(remote_addr: &str, acls: &[&Acl]) -> Result<UdpSocket, (CloseReason, String)> {
+    let mut last_err = None;
+    for addr in resolve(remote_addr, acls).await? {
+        let bind_addr = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let connected = match UdpSocket::bind(bind_addr).await {
+            Ok(socket) => socket.connect(addr).await.map(|()| socket),
+            Err(e) => Err(e),
+        };
+        match connected {
+            Ok(socket) => return Ok(socket),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    let e = last_err.expect("resolve returns at least one address");
+    Err((tunnel::io_close_reason(&e), e.to_string()))
+}
+
+// Hands each connection accepted on a reverse forward's listener to the
+// client, which dials the local target. Runs until the session ends; the
+// forward slot is held for as long as the listener is open.
+async fn reverse_accept_loop(
+    listener: TcpListener,
+    forward_id: u32,
+    _slot: QuotaGuard,
+    user: Arc<User>,
+    connections: Arc<Connections>,
+    tx: FrameSender,
+    conn_ids: Arc<ConnIds>,
+) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                util::accept_failed(&format!("reverse forward {}", forward_id), e).await;
+                continue;
+            }
+        };
+
+        let Some(conn_slot) = user.connections.acquire() else {
+            warn!("Rejecting connection from {}: connection limit reached", addr);
+            continue;
+        };
+
+        let conn_id = conn_ids.next(|id| connections.contains(id));
+        info!("New connection on reverse forward {}: {}", forward_id, addr);
+
+        let _ = stream.set_nodelay(true);
+        let mut target = vec![0x00];
+        target.extend_from_slice(&forward_id.to_be_bytes());
+
+        let tx = tx.clone();
+        let conns = connections.clone();
+        let task = async move {
+            let _conn_slot = conn_slot;
+            let pending = match conns.open(conn_id, target, &tx).await {
+                Ok(pending) => pending,
+                Err(e) => {
+                    warn!("Failed to open connection {}: {}", conn_id, e);
+                    return;
+                }
+            };
+            let reason = pending.run(stream).await;
+            conns.finish(&tx, conn_id, reason).await;
+        };
+        tokio::spawn(task.in_current_span());
+    }
+}
+
+fn send_register_result(tx: &FrameSender, result: Result<u32, &str>) {
+    let data = match result {
+        Ok(forward_id) => {
+            let mut data = vec![0x00];
+            data.extend_from_slice(&forward_id.to_be_bytes());
+            data
+        }
+        Err(reason) => {
+            let mut data = vec![0x01];
+            data.extend_from_slice(reason.as_bytes());
+            data
+        }
+    };
+    let response = Frame {
+        frame_type: FrameType::RegisterForwardResult,
+        conn_id: 0,
+        data,
+    };
+    let _ = tx.send_control(response);
+}
@@ -0,0 +1,144 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+#[cfg(unix)]
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::watch;
+use tokio::time::Instant;
+use tracing::info;
+
+// Graceful shutdown on SIGTERM or SIGINT. `run` keeps the Trigger and hands a
+// Shutdown to everything that has to wind down; once the signal arrives,
+// sessions stop taking new connections, send GoAway and give the open ones
+// the grace period to finish. A second signal cuts the grace period short.
+// The shutdown is complete when every Shutdown has been dropped.
+pub struct Trigger {
+    signals: Signals,
+    stage: watch::Sender<Stage>,
+    force_closed: Arc<AtomicUsize>,
+}
+
+struct Signals {
+    #[cfg(unix)]
+    terminate: Signal,
+    #[cfg(unix)]
+    interrupt: Signal,
+}
+
+#[derive(Clone)]
+pub struct Shutdown {
+    stage: watch::Receiver<Stage>,
+    force_closed: Arc<AtomicUsize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+enum Stage {
+    Running,
+    Draining,
+    Forced,
+}
+
+// Installs the signal handlers, so a signal that arrives before anyone waits
+// for it still counts.
+pub fn listen() -> anyhow::Result<(Trigger, Shutdown)> {
+    let (stage_tx, stage_rx) = watch::channel(Stage::Running);
+    let force_closed = Arc::new(AtomicUsize::new(0));
+    let signals = Signals {
+        #[cfg(unix)]
+        terminate: signal(SignalKind::terminate())?,
+        #[cfg(unix)]
+        interrupt: signal(SignalKind::interrupt())?,
+    };
+    let trigger = Trigger {
+        signals,
+        stage: stage_tx,
+        force_closed: force_closed.clone(),
+    };
+    let shutdown = Shutdown {
+        stage: stage_rx,
+        force_closed,
+    };
+    Ok((trigger, shutdown))
+}
+
+impl Trigger {
+    // Waits for the signal and starts the shutdown.
+    pub async fn signaled(&mut self) {
+        let name = self.signals.next().await;
+        info!("Received {}, shutting down", name);
+        self.stage.send_replace(Stage::Draining);
+    }
+
+    // Waits until every Shutdown is gone and returns how many connections
+    // were still open when their grace period ran out. Another signal in the
+    // meantime ends the grace periods right away.
+    pub async fn drained(&mut self) -> usize {
+        loop {
+            tokio::select! {
+                () = self.stage.closed() => break,
+                name = self.signals.next(), if *self.stage.borrow() != Stage::Forced => {
+                    info!("Received {} again, force-closing connections", name);
+                    self.stage.send_replace(Stage::Forced);
+                }
+            }
+        }
+        self.force_closed.load(Ordering::Relaxed)
+    }
+}
+
+impl Signals {
+    async fn next(&mut self) -> &'static str {
+        #[cfg(unix)]
+        let name = tokio::select! {
+            _ = self.terminate.recv() => "SIGTERM",
+            _ = self.interrupt.recv() => "SIGINT",
+        };
+        #[cfg(not(unix))]
+        let name = match tokio::signal::ctrl_c().await {
+            Ok(()) => "Ctrl-C",
+            Err(_) => std::future::pending().await,
+        };
+        name
+    }
+}
+
+impl Shutdown {
+    // Resolves once the shutdown has started; never if the Trigger is dropped
+    // without starting it.
+    pub async fn started(&self) {
+        self.reached(Stage::Draining).await
+    }
+
+    pub fn is_started(&self) -> bool {
+        *self.stage.borrow() >= Stage::Draining
+    }
+
+    // Whether a second signal cut the grace period short.
+    pub fn is_forced(&self) -> bool {
+        *self.stage.borrow() == Stage::Forced
+    }
+
+    pub fn force_closed(&self, count: usize) {
+        self.force_closed.fetch_add(count, Ordering::Relaxed);
+    }
+
+    // Resolves at a draining session's deadline, or sooner on a second
+    // signal; never while it isn't draining.
+    pub async fn grace_expired(&self, deadline: Option<Instant>) {
+        match deadline {
+            Some(deadline) => tokio::select! {
+                () = tokio::time::sleep_until(deadline) => {}
+                () = self.reached(Stage::Forced) => {}
+            },
+            None => std::future::pending().await,
+        }
+    }
+
+    // Takes a receiver of its own, so the futures can be polled side by side.
+    async fn reached(&self, stage: Stage) {
+        let mut receiver = self.stage.clone();
+        if receiver.wait_for(|current| *current >= stage).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
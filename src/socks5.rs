@@ -1,16 +1,144 @@
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
-pub async fn connect(
-    proxy_addr: &str,
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::lookup_host;
+
+use crate::config::{self, Socks5Config, SocksProtocol, SocksResolve};
+
+// The target as sent to the proxy: an address, or a name for the proxy to
+// resolve.
+enum Target<'a> {
+    Ip(IpAddr),
+    Domain(&'a str),
+}
+
+// SOCKS commands, the same in both versions.
+const CMD_CONNECT: u8 = 0x01;
+const CMD_BIND: u8 = 0x02;
+
+// Performs the CONNECT handshake of the configured SOCKS version on `stream`,
+// an established connection to the proxy, and returns it as a tunnel to the
+// target along with the address the proxy bound for it (host:port).
+pub async fn connect<S>(
+    mut stream: S,
+    proxy: &Socks5Config,
     target_addr: &str,
     target_port: u16,
+) -> anyhow::Result<(S, String)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let target = resolve_target(proxy, target_addr, target_port).await?;
+    let bound = request(&mut stream, proxy, CMD_CONNECT, &target, target_port).await?;
+    Ok((stream, bound))
+}
+
+// A BIND the proxy has accepted: it listens on `bound` (host:port) for one
+// connection from the peer named in the request, which `accept` waits for.
+// Protocols that need a connection back from the far end (FTP-style) tell
+// the far end to connect to `bound`.
+pub struct Bind<S> {
+    stream: S,
+    protocol: SocksProtocol,
+    pub bound: String,
+}
+
+// Performs the BIND handshake on `stream`. `peer_addr` is the host expected to
+// connect; proxies may use it to filter who gets through.
+pub async fn bind<S>(
+    mut stream: S,
+    proxy: &Socks5Config,
+    peer_addr: &str,
+    peer_port: u16,
+) -> anyhow::Result<Bind<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let peer = resolve_target(proxy, peer_addr, peer_port).await?;
+    let bound = request(&mut stream, proxy, CMD_BIND, &peer, peer_port).await?;
+    Ok(Bind {
+        stream,
+        protocol: proxy.protocol,
+        bound,
+    })
+}
+
+impl<S> Bind<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // Waits for the proxy's second reply, sent once the peer has connected,
+    // and returns the stream as a tunnel to the peer along with its address.
+    pub async fn accept(mut self) -> anyhow::Result<(S, String)> {
+        let peer = match self.protocol {
+            SocksProtocol::Socks5 => socks5_reply(&mut self.stream).await?,
+            SocksProtocol::Socks4 | SocksProtocol::Socks4a => {
+                socks4_reply(&mut self.stream).await?
+            }
+        };
+        Ok((self.stream, peer))
+    }
+}
+
+// Sends `command` for `target` in the configured SOCKS version and returns
+// the address from the reply.
+async fn request<S>(
+    stream: &mut S,
+    proxy: &Socks5Config,
+    command: u8,
+    target: &Target<'_>,
+    target_port: u16,
+) -> anyhow::Result<String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match proxy.protocol {
+        SocksProtocol::Socks5 => {
+            let username = proxy.username.as_deref();
+            let password = proxy.password.as_deref();
+            socks5_authenticate(stream, username, password).await?;
+            socks5_request(stream, command, target, target_port).await
+        }
+        SocksProtocol::Socks4 | SocksProtocol::Socks4a => {
+            let user_id = proxy.username.as_deref().unwrap_or("");
+            socks4_request(stream, command, target, target_port, user_id).await
+        }
+    }
+}
+
+async fn resolve_target<'a>(
+    proxy: &Socks5Config,
+    target_addr: &'a str,
+    target_port: u16,
+) -> anyhow::Result<Target<'a>> {
+    if let Ok(ip) = target_addr.parse::<IpAddr>() {
+        return Ok(Target::Ip(ip));
+    }
+    if proxy.protocol != SocksProtocol::Socks4 && proxy.resolve == SocksResolve::Remote {
+        return Ok(Target::Domain(target_addr));
+    }
+
+    // Neither SOCKS4 variant can send an IPv6 address.
+    let ipv4_only = proxy.protocol != SocksProtocol::Socks5;
+    let addrs: Vec<SocketAddr> = lookup_host((target_addr, target_port)).await?.collect();
+    addrs
+        .iter()
+        .find(|addr| !ipv4_only || addr.is_ipv4())
+        .map(|addr| Target::Ip(addr.ip()))
+        .ok_or_else(|| {
+            let kind = if ipv4_only { "IPv4 addresses" } else { "addresses" };
+            anyhow::anyhow!("No {} found for {}", kind, target_addr)
+        })
+}
+
+async fn socks5_authenticate<S>(
+    stream: &mut S,
     username: Option<&str>,
     password: Option<&str>,
-) -> anyhow::Result<TcpStream> {
-    let mut stream = TcpStream::connect(proxy_addr).await?;
-    stream.set_nodelay(true)?;
-
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
     let has_auth = username.is_some() && password.is_some();
 
     if has_auth {
@@ -52,6 +180,12 @@ pub async fn connect(
 
             let mut auth_resp = [0u8; 2];
             stream.read_exact(&mut auth_resp).await?;
+            if auth_resp[0] != 0x01 {
+                return Err(anyhow::anyhow!(
+                    "Invalid SOCKS5 auth response version: 0x{:02x}",
+                    auth_resp[0]
+                ));
+            }
             if auth_resp[1] != 0x00 {
                 return Err(anyhow::anyhow!("SOCKS5 authentication failed"));
             }
@@ -69,18 +203,51 @@ pub async fn connect(
         }
     }
 
-    let mut connect_req = Vec::with_capacity(1 + 1 + 1 + 1 + target_addr.len() + 2);
-    connect_req.push(0x05);
-    connect_req.push(0x01);
-    connect_req.push(0x00);
-    connect_req.push(0x03);
-    connect_req.push(target_addr.len() as u8);
-    connect_req.extend_from_slice(target_addr.as_bytes());
+    Ok(())
+}
+
+async fn socks5_request<S>(
+    stream: &mut S,
+    command: u8,
+    target: &Target<'_>,
+    target_port: u16,
+) -> anyhow::Result<String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut connect_req = Vec::with_capacity(4 + 1 + 255 + 2);
+    connect_req.extend_from_slice(&[0x05, command, 0x00]);
+    match target {
+        Target::Ip(IpAddr::V4(ip)) => {
+            connect_req.push(0x01);
+            connect_req.extend_from_slice(&ip.octets());
+        }
+        Target::Ip(IpAddr::V6(ip)) => {
+            connect_req.push(0x04);
+            connect_req.extend_from_slice(&ip.octets());
+        }
+        Target::Domain(domain) => {
+            if domain.len() > 255 {
+                return Err(anyhow::anyhow!("SOCKS5 target name too long: {}", domain));
+            }
+            connect_req.push(0x03);
+            connect_req.push(domain.len() as u8);
+            connect_req.extend_from_slice(domain.as_bytes());
+        }
+    }
     connect_req.extend_from_slice(&target_port.to_be_bytes());
 
     stream.write_all(&connect_req).await?;
     stream.flush().await?;
 
+    socks5_reply(stream).await
+}
+
+// Reads a SOCKS5 reply and returns its address (host:port).
+async fn socks5_reply<S>(stream: &mut S) -> anyhow::Result<String>
+where
+    S: AsyncRead + Unpin,
+{
     let mut resp_header = [0u8; 4];
     stream.read_exact(&mut resp_header).await?;
     if resp_header[0] != 0x05 {
@@ -99,26 +266,359 @@ pub async fn connect(
         code => return Err(anyhow::anyhow!("SOCKS5: unknown error 0x{:02x}", code)),
     }
 
-    match resp_header[3] {
+    let bound_host = match resp_header[3] {
         0x01 => {
-            let mut buf = [0u8; 4 + 2];
+            let mut buf = [0u8; 4];
             stream.read_exact(&mut buf).await?;
+            Ipv4Addr::from(buf).to_string()
         }
         0x03 => {
             let mut len_buf = [0u8; 1];
             stream.read_exact(&mut len_buf).await?;
-            let domain_len = len_buf[0] as usize;
-            let mut buf = vec![0u8; domain_len + 2];
+            let mut buf = vec![0u8; len_buf[0] as usize];
             stream.read_exact(&mut buf).await?;
+            String::from_utf8_lossy(&buf).into_owned()
         }
         0x04 => {
-            let mut buf = [0u8; 16 + 2];
+            let mut buf = [0u8; 16];
             stream.read_exact(&mut buf).await?;
+            Ipv6Addr::from(buf).to_string()
         }
         atyp => {
             return Err(anyhow::anyhow!("SOCKS5: unknown address type 0x{:02x}", atyp));
         }
+    };
+    let bound_port = stream.read_u16().await?;
+
+    Ok(config::format_host_port(&bound_host, bound_port))
+}
+
+// SOCKS4 takes an IPv4 address only; SOCKS4a also takes a name, sent after
+// the user id with 0.0.0.1 in place of the address.
+async fn socks4_request<S>(
+    stream: &mut S,
+    command: u8,
+    target: &Target<'_>,
+    target_port: u16,
+    user_id: &str,
+) -> anyhow::Result<String>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut connect_req = vec![0x04, command];
+    connect_req.extend_from_slice(&target_port.to_be_bytes());
+    match target {
+        Target::Ip(IpAddr::V4(ip)) => connect_req.extend_from_slice(&ip.octets()),
+        Target::Ip(IpAddr::V6(ip)) => {
+            return Err(anyhow::anyhow!(
+                "SOCKS4 cannot connect to IPv6 address {}",
+                ip
+            ));
+        }
+        Target::Domain(_) => connect_req.extend_from_slice(&[0, 0, 0, 1]),
+    }
+    connect_req.extend_from_slice(user_id.as_bytes());
+    connect_req.push(0x00);
+    if let Target::Domain(domain) = target {
+        connect_req.extend_from_slice(domain.as_bytes());
+        connect_req.push(0x00);
     }
 
-    Ok(stream)
+    stream.write_all(&connect_req).await?;
+    stream.flush().await?;
+
+    socks4_reply(stream).await
+}
+
+// Reads a SOCKS4 reply and returns its address (host:port).
+async fn socks4_reply<S>(stream: &mut S) -> anyhow::Result<String>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut resp = [0u8; 8];
+    stream.read_exact(&mut resp).await?;
+    if resp[0] != 0x00 {
+        return Err(anyhow::anyhow!(
+            "Invalid SOCKS4 response version: 0x{:02x}",
+            resp[0]
+        ));
+    }
+    match resp[1] {
+        0x5a => {}
+        0x5b => return Err(anyhow::anyhow!("SOCKS4: request rejected or failed")),
+        0x5c => return Err(anyhow::anyhow!("SOCKS4: identd not reachable")),
+        0x5d => return Err(anyhow::anyhow!("SOCKS4: identd user id mismatch")),
+        code => return Err(anyhow::anyhow!("SOCKS4: unknown error 0x{:02x}", code)),
+    }
+
+    let bound_port = u16::from_be_bytes([resp[2], resp[3]]);
+    let bound_ip = Ipv4Addr::new(resp[4], resp[5], resp[6], resp[7]);
+    Ok(config::format_host_port(&bound_ip.to_string(), bound_port))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    use super::*;
+
+    fn proxy(protocol: SocksProtocol, resolve: SocksResolve) -> Socks5Config {
+        Socks5Config {
+            addr: "proxy:1080".to_string(),
+            username: None,
+            password: None,
+            protocol,
+            resolve,
+        }
+    }
+
+    // A SOCKS5 proxy that accepts no-auth (or user/pass with `auth_reply`),
+    // answers the request with `reply` and returns the request it got.
+    async fn mock_socks5(
+        mut stream: DuplexStream,
+        auth_reply: Option<[u8; 2]>,
+        reply: Vec<u8>,
+    ) -> Vec<u8> {
+        let mut greeting = [0u8; 2];
+        stream.read_exact(&mut greeting).await.unwrap();
+        let mut methods = vec![0u8; greeting[1] as usize];
+        stream.read_exact(&mut methods).await.unwrap();
+        match auth_reply {
+            None => stream.write_all(&[0x05, 0x00]).await.unwrap(),
+            Some(auth_reply) => {
+                stream.write_all(&[0x05, 0x02]).await.unwrap();
+                let mut header = [0u8; 2];
+                stream.read_exact(&mut header).await.unwrap();
+                let mut user = vec![0u8; header[1] as usize];
+                stream.read_exact(&mut user).await.unwrap();
+                let mut pass = vec![0u8; stream.read_u8().await.unwrap() as usize];
+                stream.read_exact(&mut pass).await.unwrap();
+                stream.write_all(&auth_reply).await.unwrap();
+                if auth_reply != [0x01, 0x00] {
+                    return Vec::new();
+                }
+            }
+        }
+
+        let mut request = vec![0u8; 4];
+        stream.read_exact(&mut request).await.unwrap();
+        let addr_len = match request[3] {
+            0x01 => 4,
+            0x04 => 16,
+            _ => {
+                let len = stream.read_u8().await.unwrap();
+                request.push(len);
+                len as usize
+            }
+        };
+        let mut rest = vec![0u8; addr_len + 2];
+        stream.read_exact(&mut rest).await.unwrap();
+        request.extend_from_slice(&rest);
+        stream.write_all(&reply).await.unwrap();
+        request
+    }
+
+    // A SOCKS4 proxy that answers the request with `reply` and returns the
+    // request it got.
+    async fn mock_socks4(mut stream: DuplexStream, reply: [u8; 8]) -> Vec<u8> {
+        let mut request = vec![0u8; 8];
+        stream.read_exact(&mut request).await.unwrap();
+        // The user id, then the name for SOCKS4a's 0.0.0.x addresses.
+        let strings = if request[4..7] == [0, 0, 0] && request[7] != 0 { 2 } else { 1 };
+        for _ in 0..strings {
+            loop {
+                let byte = stream.read_u8().await.unwrap();
+                request.push(byte);
+                if byte == 0 {
+                    break;
+                }
+            }
+        }
+        stream.write_all(&reply).await.unwrap();
+        request
+    }
+
+    const SOCKS5_OK: [u8; 10] = [0x05, 0x00, 0x00, 0x01, 10, 0, 0, 1, 0x1f, 0x90];
+
+    async fn socks5_request_for(config: Socks5Config, host: &str) -> Vec<u8> {
+        let (client, server) = duplex(1024);
+        let mock = tokio::spawn(mock_socks5(server, None, SOCKS5_OK.to_vec()));
+        connect(client, &config, host, 443).await.unwrap();
+        mock.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn socks5_sends_ipv4_literal() {
+        let config = proxy(SocksProtocol::Socks5, SocksResolve::Remote);
+        let request = socks5_request_for(config, "192.0.2.7").await;
+        assert_eq!(request, [0x05, 0x01, 0x00, 0x01, 192, 0, 2, 7, 0x01, 0xbb]);
+    }
+
+    #[tokio::test]
+    async fn socks5_sends_ipv6_literal() {
+        let config = proxy(SocksProtocol::Socks5, SocksResolve::Remote);
+        let request = socks5_request_for(config, "2001:db8::1").await;
+        let mut expected = vec![0x05, 0x01, 0x00, 0x04];
+        expected.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        expected.extend_from_slice(&[0x01, 0xbb]);
+        assert_eq!(request, expected);
+    }
+
+    #[tokio::test]
+    async fn socks5_sends_name_for_remote_resolution() {
+        let config = proxy(SocksProtocol::Socks5, SocksResolve::Remote);
+        let request = socks5_request_for(config, "example.com").await;
+        let mut expected = vec![0x05, 0x01, 0x00, 0x03, 11];
+        expected.extend_from_slice(b"example.com");
+        expected.extend_from_slice(&[0x01, 0xbb]);
+        assert_eq!(request, expected);
+    }
+
+    #[tokio::test]
+    async fn socks5_resolves_locally() {
+        let config = proxy(SocksProtocol::Socks5, SocksResolve::Local);
+        let request = socks5_request_for(config, "localhost").await;
+        assert!(request[3] == 0x01 || request[3] == 0x04, "ATYP {}", request[3]);
+    }
+
+    #[tokio::test]
+    async fn socks5_rejects_bad_auth_reply_version() {
+        let mut config = proxy(SocksProtocol::Socks5, SocksResolve::Remote);
+        config.username = Some("user".to_string());
+        config.password = Some("pass".to_string());
+        let (client, server) = duplex(1024);
+        let mock = tokio::spawn(mock_socks5(server, Some([0x05, 0x00]), Vec::new()));
+        let err = connect(client, &config, "example.com", 443).await.unwrap_err();
+        assert!(err.to_string().contains("auth response version"), "{}", err);
+        mock.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn socks5_authenticates() {
+        let mut config = proxy(SocksProtocol::Socks5, SocksResolve::Remote);
+        config.username = Some("user".to_string());
+        config.password = Some("pass".to_string());
+        let (client, server) = duplex(1024);
+        let mock = tokio::spawn(mock_socks5(server, Some([0x01, 0x00]), SOCKS5_OK.to_vec()));
+        let (_, bound) = connect(client, &config, "example.com", 443).await.unwrap();
+        assert_eq!(bound, "10.0.0.1:8080");
+        mock.await.unwrap();
+    }
+
+    async fn socks5_bound(reply: Vec<u8>) -> anyhow::Result<String> {
+        let config = proxy(SocksProtocol::Socks5, SocksResolve::Remote);
+        let (client, server) = duplex(1024);
+        let mock = tokio::spawn(mock_socks5(server, None, reply));
+        let result = connect(client, &config, "example.com", 443).await;
+        mock.await.unwrap();
+        result.map(|(_, bound)| bound)
+    }
+
+    #[tokio::test]
+    async fn socks5_parses_bound_addresses() {
+        let bound = socks5_bound(SOCKS5_OK.to_vec()).await.unwrap();
+        assert_eq!(bound, "10.0.0.1:8080");
+
+        let mut reply = vec![0x05, 0x00, 0x00, 0x04];
+        reply.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        reply.extend_from_slice(&[0x00, 0x50]);
+        assert_eq!(socks5_bound(reply).await.unwrap(), "[2001:db8::2]:80");
+
+        let mut reply = vec![0x05, 0x00, 0x00, 0x03, 9];
+        reply.extend_from_slice(b"proxy.lan");
+        reply.extend_from_slice(&[0x04, 0x38]);
+        assert_eq!(socks5_bound(reply).await.unwrap(), "proxy.lan:1080");
+    }
+
+    #[tokio::test]
+    async fn socks5_reports_reply_errors() {
+        let reply = vec![0x05, 0x05, 0x00, 0x01, 0, 0, 0, 0, 0, 0];
+        let err = socks5_bound(reply).await.unwrap_err();
+        assert_eq!(err.to_string(), "SOCKS5: connection refused");
+
+        let reply = vec![0x05, 0x00, 0x00, 0x07, 0, 0];
+        let err = socks5_bound(reply).await.unwrap_err();
+        assert_eq!(err.to_string(), "SOCKS5: unknown address type 0x07");
+    }
+
+    #[tokio::test]
+    async fn socks5_bind_returns_both_replies() {
+        let config = proxy(SocksProtocol::Socks5, SocksResolve::Remote);
+        let (client, server) = duplex(1024);
+        let mock = tokio::spawn(async move {
+            let mut second = SOCKS5_OK.to_vec();
+            second.extend_from_slice(&[0x05, 0x00, 0x00, 0x01, 192, 0, 2, 9, 0x30, 0x39]);
+            mock_socks5(server, None, second).await
+        });
+        let bind = bind(client, &config, "192.0.2.9", 0).await.unwrap();
+        assert_eq!(bind.bound, "10.0.0.1:8080");
+        let (_, peer) = bind.accept().await.unwrap();
+        assert_eq!(peer, "192.0.2.9:12345");
+        assert_eq!(mock.await.unwrap()[1], CMD_BIND);
+    }
+
+    async fn socks4_exchange(
+        config: Socks5Config,
+        host: &str,
+        reply: [u8; 8],
+    ) -> (anyhow::Result<String>, Vec<u8>) {
+        let (client, server) = duplex(1024);
+        let mock = tokio::spawn(mock_socks4(server, reply));
+        let result = connect(client, &config, host, 80).await.map(|(_, bound)| bound);
+        (result, mock.await.unwrap())
+    }
+
+    const SOCKS4_OK: [u8; 8] = [0x00, 0x5a, 0x1f, 0x90, 10, 0, 0, 1];
+
+    #[tokio::test]
+    async fn socks4_sends_address_and_user_id() {
+        let mut config = proxy(SocksProtocol::Socks4, SocksResolve::Remote);
+        config.username = Some("bob".to_string());
+        let (result, request) = socks4_exchange(config, "192.0.2.7", SOCKS4_OK).await;
+        assert_eq!(result.unwrap(), "10.0.0.1:8080");
+        assert_eq!(request, [0x04, 0x01, 0x00, 0x50, 192, 0, 2, 7, b'b', b'o', b'b', 0]);
+    }
+
+    #[tokio::test]
+    async fn socks4a_sends_name() {
+        let config = proxy(SocksProtocol::Socks4a, SocksResolve::Remote);
+        let (result, request) = socks4_exchange(config, "example.com", SOCKS4_OK).await;
+        assert!(result.is_ok());
+        let mut expected = vec![0x04, 0x01, 0x00, 0x50, 0, 0, 0, 1, 0];
+        expected.extend_from_slice(b"example.com\0");
+        assert_eq!(request, expected);
+    }
+
+    #[tokio::test]
+    async fn socks4_reply_codes() {
+        let cases = [
+            (0x5b, "SOCKS4: request rejected or failed"),
+            (0x5c, "SOCKS4: identd not reachable"),
+            (0x5d, "SOCKS4: identd user id mismatch"),
+            (0x42, "SOCKS4: unknown error 0x42"),
+        ];
+        for (code, message) in cases {
+            let config = proxy(SocksProtocol::Socks4, SocksResolve::Remote);
+            let reply = [0x00, code, 0, 0, 0, 0, 0, 0];
+            let (result, _) = socks4_exchange(config, "192.0.2.7", reply).await;
+            assert_eq!(result.unwrap_err().to_string(), message);
+        }
+
+        let config = proxy(SocksProtocol::Socks4, SocksResolve::Remote);
+        let reply = [0x04, 0x5a, 0, 0, 0, 0, 0, 0];
+        let (result, _) = socks4_exchange(config, "192.0.2.7", reply).await;
+        let err = result.unwrap_err();
+        assert_eq!(err.to_string(), "Invalid SOCKS4 response version: 0x04");
+    }
+
+    #[tokio::test]
+    async fn socks4_variants_resolve_to_ipv4_only() {
+        for protocol in [SocksProtocol::Socks4, SocksProtocol::Socks4a] {
+            let config = proxy(protocol, SocksResolve::Local);
+            match resolve_target(&config, "localhost", 80).await.unwrap() {
+                Target::Ip(ip) => assert!(ip.is_ipv4(), "{:?} picked {}", protocol, ip),
+                Target::Domain(_) => panic!("{:?} sent a name", protocol),
+            }
+        }
+    }
 }
@@ -0,0 +1,329 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use std::time::Duration;
+
+use subtle::ConstantTimeEq;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::config;
+
+// How long a client may take to get through the handshake and send its
+// request.
+const NEGOTIATION_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocksVersion {
+    V4,
+    V5,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Connect,
+    Bind,
+    UdpAssociate,
+}
+
+// A request read from a local SOCKS client. The caller answers a CONNECT with
+// `reply` once the tunneled connection has succeeded or failed, a UDP
+// ASSOCIATE with `reply_bound` once its relay socket is ready, and a BIND with
+// `reply_address` twice: once listening, and again when the peer connects.
+pub struct SocksRequest {
+    pub version: SocksVersion,
+    pub command: Command,
+    pub host: String,
+    pub port: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Reply {
+    Succeeded,
+    GeneralFailure,
+    NotAllowed,
+    HostUnreachable,
+    ConnectionRefused,
+    TtlExpired,
+}
+
+impl SocksRequest {
+    pub fn target(&self) -> String {
+        config::format_host_port(&self.host, self.port)
+    }
+
+    pub async fn reply(&self, stream: &mut TcpStream, reply: Reply) -> anyhow::Result<()> {
+        let response: &[u8] = match (self.version, reply) {
+            (SocksVersion::V4, Reply::Succeeded) => &[0x00, 0x5a, 0, 0, 0, 0, 0, 0],
+            (SocksVersion::V4, _) => &[0x00, 0x5b, 0, 0, 0, 0, 0, 0],
+            (SocksVersion::V5, reply) => {
+                let code = match reply {
+                    Reply::Succeeded => 0x00,
+                    Reply::GeneralFailure => 0x01,
+                    Reply::NotAllowed => 0x02,
+                    Reply::HostUnreachable => 0x04,
+                    Reply::ConnectionRefused => 0x05,
+                    Reply::TtlExpired => 0x06,
+                };
+                &[0x05, code, 0x00, 0x01, 0, 0, 0, 0, 0, 0][..]
+            }
+        };
+        stream.write_all(response).await?;
+        stream.flush().await?;
+        Ok(())
+    }
+
+    pub async fn reply_bound(
+        &self,
+        stream: &mut TcpStream,
+        bound: SocketAddr,
+    ) -> anyhow::Result<()> {
+        self.reply_address(stream, &bound.to_string()).await
+    }
+
+    // A success reply carrying `addr` (host:port). SOCKS4 replies only have
+    // room for an IPv4 address; 0.0.0.0 there tells the client to use the
+    // proxy's.
+    pub async fn reply_address(&self, stream: &mut TcpStream, addr: &str) -> anyhow::Result<()> {
+        let (host, port) = config::parse_host_port(addr)?;
+        let response = match self.version {
+            SocksVersion::V4 => {
+                let ip = host.parse::<Ipv4Addr>().unwrap_or(Ipv4Addr::UNSPECIFIED);
+                let mut response = vec![0x00, 0x5a];
+                response.extend_from_slice(&port.to_be_bytes());
+                response.extend_from_slice(&ip.octets());
+                response
+            }
+            SocksVersion::V5 => {
+                let mut response = vec![0x05, 0x00, 0x00];
+                response.extend_from_slice(&encode_addr(&host, port));
+                response
+            }
+        };
+        stream.write_all(&response).await?;
+        stream.flush().await?;
+        Ok(())
+    }
+}
+
+// Parses the header of a datagram sent to a UDP ASSOCIATE relay socket.
+// Returns the destination host and port and the payload offset, or None for
+// malformed and fragmented datagrams, which are dropped.
+pub fn parse_udp_header(datagram: &[u8]) -> Option<(String, u16, usize)> {
+    if datagram.len() < 4 || datagram[2] != 0x00 {
+        return None;
+    }
+    let (host, rest) = match datagram[3] {
+        0x01 => {
+            let addr: [u8; 4] = datagram.get(4..8)?.try_into().ok()?;
+            (Ipv4Addr::from(addr).to_string(), 8)
+        }
+        0x03 => {
+            let len = *datagram.get(4)? as usize;
+            let name = datagram.get(5..5 + len)?;
+            (String::from_utf8(name.to_vec()).ok()?, 5 + len)
+        }
+        0x04 => {
+            let addr: [u8; 16] = datagram.get(4..20)?.try_into().ok()?;
+            (Ipv6Addr::from(addr).to_string(), 20)
+        }
+        _ => return None,
+    };
+    let port = datagram.get(rest..rest + 2)?;
+    Some((host, u16::from_be_bytes([port[0], port[1]]), rest + 2))
+}
+
+// The header a UDP ASSOCIATE relay puts in front of a datagram from `host`.
+pub fn udp_header(host: &str, port: u16) -> Vec<u8> {
+    let mut header = vec![0x00, 0x00, 0x00];
+    header.extend_from_slice(&encode_addr(host, port));
+    header
+}
+
+fn encode_addr(host: &str, port: u16) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            encoded.push(0x01);
+            encoded.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            encoded.push(0x04);
+            encoded.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            encoded.push(0x03);
+            encoded.push(host.len().min(255) as u8);
+            encoded.extend_from_slice(&host.as_bytes()[..host.len().min(255)]);
+        }
+    }
+    encoded.extend_from_slice(&port.to_be_bytes());
+    encoded
+}
+
+// Reads a SOCKS4, SOCKS4a or SOCKS5 handshake up to and including the
+// request. With `credentials` set, SOCKS5 clients must authenticate
+// with that username and password and SOCKS4 clients are refused, since
+// SOCKS4 has no way to carry a password. A client that takes longer than
+// NEGOTIATION_TIMEOUT is given up on.
+pub async fn accept(
+    stream: &mut TcpStream,
+    credentials: Option<(&str, &str)>,
+) -> anyhow::Result<SocksRequest> {
+    tokio::time::timeout(NEGOTIATION_TIMEOUT, negotiate(stream, credentials))
+        .await
+        .map_err(|_| anyhow::anyhow!("Timed out after {:?}", NEGOTIATION_TIMEOUT))?
+}
+
+async fn negotiate(
+    stream: &mut TcpStream,
+    credentials: Option<(&str, &str)>,
+) -> anyhow::Result<SocksRequest> {
+    let mut version = [0u8; 1];
+    stream.read_exact(&mut version).await?;
+    match version[0] {
+        0x04 => accept_v4(stream, credentials.is_some()).await,
+        0x05 => accept_v5(stream, credentials).await,
+        v => Err(anyhow::anyhow!("Unsupported SOCKS version: 0x{:02x}", v)),
+    }
+}
+
+async fn accept_v4(stream: &mut TcpStream, auth_required: bool) -> anyhow::Result<SocksRequest> {
+    let mut header = [0u8; 7];
+    stream.read_exact(&mut header).await?;
+    let command = header[0];
+    let port = u16::from_be_bytes([header[1], header[2]]);
+    let ip = Ipv4Addr::new(header[3], header[4], header[5], header[6]);
+    let _user_id = read_null_terminated(stream).await?;
+
+    // SOCKS4a: an address of 0.0.0.x (x != 0) means a hostname follows.
+    let host = if ip.octets()[..3] == [0, 0, 0] && ip.octets()[3] != 0 {
+        read_null_terminated(stream).await?
+    } else {
+        ip.to_string()
+    };
+
+    let request = SocksRequest {
+        version: SocksVersion::V4,
+        command: if command == 0x02 { Command::Bind } else { Command::Connect },
+        host,
+        port,
+    };
+    if auth_required {
+        request.reply(stream, Reply::NotAllowed).await?;
+        return Err(anyhow::anyhow!("SOCKS4 request refused: authentication is required"));
+    }
+    if command != 0x01 && command != 0x02 {
+        request.reply(stream, Reply::GeneralFailure).await?;
+        return Err(anyhow::anyhow!("Unsupported SOCKS4 command: 0x{:02x}", command));
+    }
+    Ok(request)
+}
+
+async fn accept_v5(
+    stream: &mut TcpStream,
+    credentials: Option<(&str, &str)>,
+) -> anyhow::Result<SocksRequest> {
+    let mut nmethods = [0u8; 1];
+    stream.read_exact(&mut nmethods).await?;
+    let mut methods = vec![0u8; nmethods[0] as usize];
+    stream.read_exact(&mut methods).await?;
+
+    let method = if credentials.is_some() { 0x02 } else { 0x00 };
+    if !methods.contains(&method) {
+        stream.write_all(&[0x05, 0xff]).await?;
+        return Err(anyhow::anyhow!("SOCKS5 client offered no acceptable auth method"));
+    }
+    stream.write_all(&[0x05, method]).await?;
+    stream.flush().await?;
+
+    if let Some((username, password)) = credentials {
+        let mut header = [0u8; 2];
+        stream.read_exact(&mut header).await?;
+        let mut user = vec![0u8; header[1] as usize];
+        stream.read_exact(&mut user).await?;
+        let mut pass_len = [0u8; 1];
+        stream.read_exact(&mut pass_len).await?;
+        let mut pass = vec![0u8; pass_len[0] as usize];
+        stream.read_exact(&mut pass).await?;
+
+        // Compared in constant time, both fields either way, so the time taken
+        // doesn't tell how much of a guess was right.
+        let matches = user.ct_eq(username.as_bytes()) & pass.ct_eq(password.as_bytes());
+        if !bool::from(matches) {
+            stream.write_all(&[0x01, 0x01]).await?;
+            return Err(anyhow::anyhow!("SOCKS5 authentication failed"));
+        }
+        stream.write_all(&[0x01, 0x00]).await?;
+        stream.flush().await?;
+    }
+
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+    if header[0] != 0x05 {
+        return Err(anyhow::anyhow!("Invalid SOCKS5 request version"));
+    }
+    let command = header[1];
+
+    let host = match header[3] {
+        0x01 => {
+            let mut addr = [0u8; 4];
+            stream.read_exact(&mut addr).await?;
+            Ipv4Addr::from(addr).to_string()
+        }
+        0x03 => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len).await?;
+            let mut name = vec![0u8; len[0] as usize];
+            stream.read_exact(&mut name).await?;
+            String::from_utf8(name).map_err(|_| anyhow::anyhow!("Invalid SOCKS5 hostname"))?
+        }
+        0x04 => {
+            let mut addr = [0u8; 16];
+            stream.read_exact(&mut addr).await?;
+            Ipv6Addr::from(addr).to_string()
+        }
+        atyp => {
+            stream
+                .write_all(&[0x05, 0x08, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+                .await?;
+            return Err(anyhow::anyhow!("SOCKS5: unknown address type 0x{:02x}", atyp));
+        }
+    };
+    let mut port = [0u8; 2];
+    stream.read_exact(&mut port).await?;
+
+    let command = match command {
+        0x01 => Command::Connect,
+        0x02 => Command::Bind,
+        0x03 => Command::UdpAssociate,
+        _ => {
+            stream
+                .write_all(&[0x05, 0x07, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+                .await?;
+            return Err(anyhow::anyhow!("Unsupported SOCKS5 command: 0x{:02x}", command));
+        }
+    };
+
+    Ok(SocksRequest {
+        version: SocksVersion::V5,
+        command,
+        host,
+        port: u16::from_be_bytes(port),
+    })
+}
+
+async fn read_null_terminated(stream: &mut TcpStream) -> anyhow::Result<String> {
+    let mut bytes = Vec::new();
+    loop {
+        let mut byte = [0u8; 1];
+        stream.read_exact(&mut byte).await?;
+        if byte[0] == 0 {
+            break;
+        }
+        if bytes.len() >= 255 {
+            return Err(anyhow::anyhow!("SOCKS4 field too long"));
+        }
+        bytes.push(byte[0]);
+    }
+    String::from_utf8(bytes).map_err(|_| anyhow::anyhow!("Invalid SOCKS4 field"))
+}
@@ -0,0 +1,117 @@
+use std::sync::Arc;
+
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::RootCertStore;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::config::{self, ClientTlsConfig, ServerTlsConfig};
+
+// The control connection as the frame layer sees it: a plain TCP stream or
+// one wrapped in TLS.
+pub trait Stream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Stream for T {}
+
+pub type BoxedStream = Box<dyn Stream>;
+
+// Without `ca_file` the server certificate is checked against the built-in
+// web PKI roots; with it, only against the given CA (or self-signed)
+// certificates. A client certificate is presented when `cert_file` and
+// `key_file` are both set.
+pub fn client_connector(config: &ClientTlsConfig) -> anyhow::Result<TlsConnector> {
+    Ok(TlsConnector::from(Arc::new(client_config(config)?)))
+}
+
+pub fn client_config(config: &ClientTlsConfig) -> anyhow::Result<rustls::ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    match &config.ca_file {
+        Some(ca_file) => {
+            for cert in load_certs(ca_file)? {
+                roots
+                    .add(cert)
+                    .map_err(|e| anyhow::anyhow!("Invalid CA certificate in {}: {}", ca_file, e))?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+
+    let builder = rustls::ClientConfig::builder().with_root_certificates(roots);
+    let tls_config = match (&config.cert_file, &config.key_file) {
+        (Some(cert_file), Some(key_file)) => builder
+            .with_client_auth_cert(load_certs(cert_file)?, load_key(key_file)?)
+            .map_err(|e| anyhow::anyhow!("Invalid client certificate: {}", e))?,
+        (None, None) => builder.with_no_client_auth(),
+        _ => {
+            return Err(anyhow::anyhow!(
+                "TLS cert_file and key_file must be set together"
+            ));
+        }
+    };
+    Ok(tls_config)
+}
+
+// The name the server certificate is checked against and sent as SNI:
+// `server_name` if set, otherwise the host part of `server_addr`.
+pub fn server_name(
+    config: &ClientTlsConfig,
+    server_addr: &str,
+) -> anyhow::Result<ServerName<'static>> {
+    let name = match &config.server_name {
+        Some(name) => name.clone(),
+        None => config::parse_host_port(server_addr)?.0,
+    };
+    ServerName::try_from(name.clone())
+        .map_err(|_| anyhow::anyhow!("Invalid TLS server name: {}", name))
+}
+
+// With `client_ca_file` set, clients must present a certificate issued by
+// that CA in addition to knowing a token.
+pub fn server_acceptor(config: &ServerTlsConfig) -> anyhow::Result<TlsAcceptor> {
+    Ok(TlsAcceptor::from(Arc::new(server_config(config)?)))
+}
+
+pub fn server_config(config: &ServerTlsConfig) -> anyhow::Result<rustls::ServerConfig> {
+    let builder = rustls::ServerConfig::builder();
+    let builder = match &config.client_ca_file {
+        Some(ca_file) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(ca_file)? {
+                roots
+                    .add(cert)
+                    .map_err(|e| anyhow::anyhow!("Invalid CA certificate in {}: {}", ca_file, e))?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .build()
+                .map_err(|e| anyhow::anyhow!("Invalid client CA: {}", e))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let tls_config = builder
+        .with_single_cert(load_certs(&config.cert_file)?, load_key(&config.key_file)?)
+        .map_err(|e| anyhow::anyhow!("Invalid server certificate: {}", e))?;
+    Ok(tls_config)
+}
+
+fn load_certs(path: &str) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let pem = std::fs::read(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read certificate file {}: {}", path, e))?;
+    let certs = rustls_pemfile::certs(&mut pem.as_slice())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| anyhow::anyhow!("Invalid certificate file {}: {}", path, e))?;
+    if certs.is_empty() {
+        return Err(anyhow::anyhow!("No certificates found in {}", path));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &str) -> anyhow::Result<PrivateKeyDer<'static>> {
+    let pem = std::fs::read(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read key file {}: {}", path, e))?;
+    rustls_pemfile::private_key(&mut pem.as_slice())
+        .map_err(|e| anyhow::anyhow!("Invalid key file {}: {}", path, e))?
+        .ok_or_else(|| anyhow::anyhow!("No private key found in {}", path))
+}
@@ -0,0 +1,505 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use rustls::pki_types::ServerName;
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use tracing::info;
+
+use crate::config::{
+    self, ClientConfig, HopConfig, JumpHostConfig, ServerConfig, Socks5Config,
+    UpstreamHttpProxyConfig,
+};
+use crate::http_connect;
+use crate::jump;
+use crate::quic;
+use crate::socks5;
+use crate::tls::{self, BoxedStream};
+use crate::util::accept_failed;
+use crate::websocket;
+
+// Transports carry the control connection between client and server, and the
+// session on top of them only ever sees the resulting `Link`. A base
+// transport reaches the server (directly, through a proxy or over a Unix
+// socket, or over QUIC); TLS and then WebSocket wrap another one. A new
+// transport implements `Transport` and/or `Listener` and gets picked from
+// the config in `client_transport` / `server_listeners`.
+
+// Addresses with this prefix name a Unix domain socket instead of host:port.
+const UNIX_PREFIX: &str = "unix:";
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+// An established control connection.
+pub struct Link {
+    pub stream: BoxedStream,
+    // Set over QUIC, where tunneled connections get streams of their own.
+    pub quic: Option<quinn::Connection>,
+}
+
+impl Link {
+    pub fn new(stream: BoxedStream) -> Self {
+        Link { stream, quic: None }
+    }
+}
+
+// How the client reaches the server; `connect` is called once per session.
+pub trait Transport: Send + Sync {
+    fn connect(&self) -> BoxFuture<'_, anyhow::Result<Link>>;
+}
+
+// Where the server takes control connections from.
+pub trait Listener: Send {
+    fn accept(&mut self) -> BoxFuture<'_, anyhow::Result<Accepted>>;
+}
+
+// A control connection the server has accepted. `link` completes whatever
+// handshakes the listener's layers need and runs in the connection's own
+// task, so a slow peer doesn't hold up the others. It yields None when the
+// peer turned out not to be a client and has been answered already.
+pub struct Accepted {
+    pub peer: String,
+    pub link: BoxFuture<'static, anyhow::Result<Option<Link>>>,
+}
+
+impl Accepted {
+    fn ready(peer: String, stream: BoxedStream) -> Self {
+        Accepted {
+            peer,
+            link: Box::pin(async move { Ok(Some(Link::new(stream))) }),
+        }
+    }
+
+    // Layers another handshake over the stream once the ones below are done.
+    fn and_then<F, Fut>(self, layer: F) -> Self
+    where
+        F: FnOnce(BoxedStream) -> Fut + Send + 'static,
+        Fut: Future<Output = anyhow::Result<Option<BoxedStream>>> + Send,
+    {
+        let link = self.link;
+        Accepted {
+            peer: self.peer,
+            link: Box::pin(async move {
+                match link.await? {
+                    Some(link) => Ok(layer(link.stream).await?.map(Link::new)),
+                    None => Ok(None),
+                }
+            }),
+        }
+    }
+}
+
+pub fn client_transport(config: &ClientConfig) -> anyhow::Result<Box<dyn Transport>> {
+    let unix_path = config.server_addr.strip_prefix(UNIX_PREFIX);
+    let has_proxy =
+        config.socks5.is_some() || config.upstream_http_proxy.is_some() || !config.hops.is_empty();
+
+    if let Some(quic_config) = &config.quic {
+        if config.websocket.is_some() || has_proxy || unix_path.is_some() {
+            return Err(anyhow::anyhow!(
+                "QUIC cannot be combined with [websocket], a proxy or a Unix socket"
+            ));
+        }
+        return Ok(Box::new(quic::QuicTransport::new(
+            quic_config,
+            config.tls.as_ref(),
+            &config.server_addr,
+        )?));
+    }
+
+    // [socks5] and [upstream_http_proxy] are shorthands for a single hop.
+    let hops = match (&config.socks5, &config.upstream_http_proxy) {
+        _ if !config.hops.is_empty() => {
+            if config.socks5.is_some() || config.upstream_http_proxy.is_some() {
+                return Err(anyhow::anyhow!(
+                    "[[hops]] cannot be combined with [socks5] or [upstream_http_proxy]"
+                ));
+            }
+            config.hops.clone()
+        }
+        (Some(_), Some(_)) => {
+            return Err(anyhow::anyhow!(
+                "[socks5] and [upstream_http_proxy] cannot be combined"
+            ));
+        }
+        (Some(socks5_config), None) => vec![HopConfig::Socks5(socks5_config.clone())],
+        (None, Some(proxy_config)) => vec![HopConfig::Http(proxy_config.clone())],
+        (None, None) if unix_path.is_none() => {
+            match http_connect::proxy_from_env(&config.server_addr)? {
+                Some(proxy_config) => vec![HopConfig::Http(proxy_config)],
+                None => Vec::new(),
+            }
+        }
+        (None, None) => Vec::new(),
+    };
+
+    let mut transport: Box<dyn Transport> = match unix_path {
+        Some(_) if !hops.is_empty() => {
+            return Err(anyhow::anyhow!(
+                "A Unix socket cannot be reached through a proxy"
+            ));
+        }
+        #[cfg(unix)]
+        Some(path) => Box::new(UnixTransport {
+            path: path.to_string(),
+        }),
+        #[cfg(not(unix))]
+        Some(_) => {
+            return Err(anyhow::anyhow!(
+                "Unix sockets are not supported on this platform"
+            ));
+        }
+        None => Box::new(TcpTransport {
+            addr: hops
+                .first()
+                .map_or(config.server_addr.as_str(), HopConfig::addr)
+                .to_string(),
+        }),
+    };
+
+    // Each hop is asked to connect to the next one, the last to the server.
+    for (i, hop) in hops.iter().enumerate() {
+        let target = hops
+            .get(i + 1)
+            .map_or(config.server_addr.as_str(), HopConfig::addr);
+        transport = hop_transport(transport, hop, target)?;
+    }
+
+    if let Some(tls_config) = &config.tls {
+        if unix_path.is_some() && tls_config.server_name.is_none() {
+            return Err(anyhow::anyhow!(
+                "TLS over a Unix socket requires server_name"
+            ));
+        }
+        transport = Box::new(TlsTransport {
+            inner: transport,
+            connector: tls::client_connector(tls_config)?,
+            server_name: tls::server_name(tls_config, &config.server_addr)?,
+        });
+    }
+
+    if let Some(ws_config) = &config.websocket {
+        let scheme = if config.tls.is_some() { "wss" } else { "ws" };
+        let host = match (&ws_config.host, unix_path) {
+            (Some(host), _) => host.as_str(),
+            (None, Some(_)) => "localhost",
+            (None, None) => config.server_addr.as_str(),
+        };
+        transport = Box::new(WebSocketTransport {
+            inner: transport,
+            url: format!("{}://{}{}", scheme, host, ws_config.path),
+        });
+    }
+
+    Ok(transport)
+}
+
+// The server's listeners: `listen_addr` (TCP or a Unix socket) with TLS and
+// WebSocket on top as configured, and the QUIC endpoint if there is one.
+pub async fn server_listeners(config: &ServerConfig) -> anyhow::Result<Vec<Box<dyn Listener>>> {
+    let mut listener: Box<dyn Listener> = match config.listen_addr.strip_prefix(UNIX_PREFIX) {
+        #[cfg(unix)]
+        Some(path) => Box::new(bind_unix(path)?),
+        #[cfg(not(unix))]
+        Some(_) => {
+            return Err(anyhow::anyhow!(
+                "Unix sockets are not supported on this platform"
+            ));
+        }
+        None => Box::new(TcpListener::bind(&config.listen_addr).await?),
+    };
+    info!("Server listening on {}", config.listen_addr);
+
+    if let Some(tls_config) = &config.tls {
+        listener = Box::new(TlsListener {
+            inner: listener,
+            acceptor: tls::server_acceptor(tls_config)?,
+        });
+    }
+    if let Some(ws_config) = &config.websocket {
+        listener = Box::new(WebSocketListener {
+            inner: listener,
+            endpoint: Arc::new(websocket::Endpoint::from_config(ws_config)?),
+        });
+    }
+
+    let mut listeners = vec![listener];
+    if let Some(quic_config) = &config.quic {
+        let tls_config = config
+            .tls
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("QUIC requires a [tls] certificate"))?;
+        listeners.push(Box::new(quic::server_endpoint(quic_config, tls_config)?));
+        info!("Server listening on {}/udp (QUIC)", quic_config.listen_addr);
+    }
+    Ok(listeners)
+}
+
+fn hop_transport(
+    inner: Box<dyn Transport>,
+    hop: &HopConfig,
+    target: &str,
+) -> anyhow::Result<Box<dyn Transport>> {
+    Ok(match hop {
+        HopConfig::Socks5(proxy) => {
+            let (host, port) = config::parse_host_port(target)?;
+            Box::new(Socks5Transport {
+                inner,
+                proxy: proxy.clone(),
+                host,
+                port,
+            })
+        }
+        HopConfig::Http(proxy) => Box::new(HttpConnectTransport {
+            inner,
+            proxy: proxy.clone(),
+            target: target.to_string(),
+        }),
+        HopConfig::Kproxy(jump) => {
+            let inner: Box<dyn Transport> = match &jump.tls {
+                Some(tls_config) => Box::new(TlsTransport {
+                    inner,
+                    connector: tls::client_connector(tls_config)?,
+                    server_name: tls::server_name(tls_config, &jump.addr)?,
+                }),
+                None => inner,
+            };
+            Box::new(JumpTransport {
+                inner,
+                jump: jump.clone(),
+                target: target.to_string(),
+            })
+        }
+    })
+}
+
+struct TcpTransport {
+    addr: String,
+}
+
+impl Transport for TcpTransport {
+    fn connect(&self) -> BoxFuture<'_, anyhow::Result<Link>> {
+        Box::pin(async move {
+            let stream = TcpStream::connect(&self.addr)
+                .await
+                .map_err(|e| anyhow::anyhow!("Failed to connect to {}: {}", self.addr, e))?;
+            stream.set_nodelay(true)?;
+            info!("Connected to {}", self.addr);
+            Ok(Link::new(Box::new(stream)))
+        })
+    }
+}
+
+struct Socks5Transport {
+    inner: Box<dyn Transport>,
+    proxy: Socks5Config,
+    host: String,
+    port: u16,
+}
+
+impl Transport for Socks5Transport {
+    fn connect(&self) -> BoxFuture<'_, anyhow::Result<Link>> {
+        Box::pin(async move {
+            let link = self.inner.connect().await?;
+            let (stream, bound) =
+                socks5::connect(link.stream, &self.proxy, &self.host, self.port).await?;
+            let target = config::format_host_port(&self.host, self.port);
+            info!(
+                "Connected to {} via {} proxy {} (bound {})",
+                target,
+                self.proxy.protocol.name(),
+                self.proxy.addr,
+                bound
+            );
+            Ok(Link::new(stream))
+        })
+    }
+}
+
+struct HttpConnectTransport {
+    inner: Box<dyn Transport>,
+    proxy: UpstreamHttpProxyConfig,
+    target: String,
+}
+
+impl Transport for HttpConnectTransport {
+    fn connect(&self) -> BoxFuture<'_, anyhow::Result<Link>> {
+        Box::pin(async move {
+            let link = self.inner.connect().await?;
+            let stream = http_connect::connect(link.stream, &self.proxy, &self.target).await?;
+            info!(
+                "Connected to {} via HTTP proxy {}",
+                self.target, self.proxy.addr
+            );
+            Ok(Link::new(stream))
+        })
+    }
+}
+
+struct JumpTransport {
+    inner: Box<dyn Transport>,
+    jump: JumpHostConfig,
+    target: String,
+}
+
+impl Transport for JumpTransport {
+    fn connect(&self) -> BoxFuture<'_, anyhow::Result<Link>> {
+        Box::pin(async move {
+            let link = self.inner.connect().await?;
+            let stream = jump::connect(link.stream, &self.jump, &self.target).await?;
+            info!(
+                "Connected to {} via jump host {}",
+                self.target, self.jump.addr
+            );
+            Ok(Link::new(stream))
+        })
+    }
+}
+
+#[cfg(unix)]
+struct UnixTransport {
+    path: String,
+}
+
+#[cfg(unix)]
+impl Transport for UnixTransport {
+    fn connect(&self) -> BoxFuture<'_, anyhow::Result<Link>> {
+        Box::pin(async move {
+            let stream = UnixStream::connect(&self.path)
+                .await
+                .map_err(|e| anyhow::anyhow!("Failed to connect to {}: {}", self.path, e))?;
+            info!("Connected to {}{}", UNIX_PREFIX, self.path);
+            Ok(Link::new(Box::new(stream)))
+        })
+    }
+}
+
+struct TlsTransport {
+    inner: Box<dyn Transport>,
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+}
+
+impl Transport for TlsTransport {
+    fn connect(&self) -> BoxFuture<'_, anyhow::Result<Link>> {
+        Box::pin(async move {
+            let link = self.inner.connect().await?;
+            let stream = self
+                .connector
+                .connect(self.server_name.clone(), link.stream)
+                .await?;
+            info!("TLS established with {}", self.server_name.to_str());
+            Ok(Link::new(Box::new(stream)))
+        })
+    }
+}
+
+struct WebSocketTransport {
+    inner: Box<dyn Transport>,
+    url: String,
+}
+
+impl Transport for WebSocketTransport {
+    fn connect(&self) -> BoxFuture<'_, anyhow::Result<Link>> {
+        Box::pin(async move {
+            let link = self.inner.connect().await?;
+            let stream = websocket::connect(link.stream, &self.url).await?;
+            info!("WebSocket established with {}", self.url);
+            Ok(Link::new(stream))
+        })
+    }
+}
+
+impl Listener for TcpListener {
+    fn accept(&mut self) -> BoxFuture<'_, anyhow::Result<Accepted>> {
+        Box::pin(async move {
+            let (stream, addr) = loop {
+                match TcpListener::accept(self).await {
+                    Ok(accepted) => break accepted,
+                    Err(e) => {
+                        let label = match self.local_addr() {
+                            Ok(local) => format!("listener {}", local),
+                            Err(_) => "listener".to_string(),
+                        };
+                        accept_failed(&label, e).await
+                    }
+                }
+            };
+            // A peer that is already gone again fails in its session instead.
+            let _ = stream.set_nodelay(true);
+            Ok(Accepted::ready(addr.to_string(), Box::new(stream)))
+        })
+    }
+}
+
+// Binds `path`, replacing the socket a previous run left behind.
+#[cfg(unix)]
+pub fn bind_unix(path: &str) -> anyhow::Result<UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if let Ok(metadata) = std::fs::symlink_metadata(path)
+        && metadata.file_type().is_socket()
+    {
+        std::fs::remove_file(path)?;
+    }
+    UnixListener::bind(path).map_err(|e| anyhow::anyhow!("Failed to bind {}: {}", path, e))
+}
+
+#[cfg(unix)]
+impl Listener for UnixListener {
+    fn accept(&mut self) -> BoxFuture<'_, anyhow::Result<Accepted>> {
+        Box::pin(async move {
+            // Unix socket clients are usually unnamed, so name the socket
+            // they came in on instead.
+            let name = match self.local_addr()?.as_pathname() {
+                Some(path) => format!("{}{}", UNIX_PREFIX, path.display()),
+                None => UNIX_PREFIX.to_string(),
+            };
+            let stream = loop {
+                match UnixListener::accept(self).await {
+                    Ok((stream, _)) => break stream,
+                    Err(e) => accept_failed(&format!("listener {}", name), e).await,
+                }
+            };
+            Ok(Accepted::ready(name, Box::new(stream)))
+        })
+    }
+}
+
+struct TlsListener {
+    inner: Box<dyn Listener>,
+    acceptor: TlsAcceptor,
+}
+
+impl Listener for TlsListener {
+    fn accept(&mut self) -> BoxFuture<'_, anyhow::Result<Accepted>> {
+        Box::pin(async move {
+            let acceptor = self.acceptor.clone();
+            Ok(self.inner.accept().await?.and_then(|stream| async move {
+                let stream: BoxedStream = Box::new(acceptor.accept(stream).await?);
+                Ok(Some(stream))
+            }))
+        })
+    }
+}
+
+struct WebSocketListener {
+    inner: Box<dyn Listener>,
+    endpoint: Arc<websocket::Endpoint>,
+}
+
+impl Listener for WebSocketListener {
+    fn accept(&mut self) -> BoxFuture<'_, anyhow::Result<Accepted>> {
+        Box::pin(async move {
+            let endpoint = self.endpoint.clone();
+            Ok(self
+                .inner
+                .accept()
+                .await?
+                .and_then(|stream| async move { endpoint.accept(stream).await }))
+        })
+    }
+}
@@ -0,0 +1,688 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use quinn::VarInt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::sync::{mpsc, watch, Notify, Semaphore};
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn, Instrument};
+
+use crate::crypto::FrameCipher;
+use crate::protocol::{self, CloseReason, Frame, FrameReceiver, FrameSender, FrameType};
+use crate::quic;
+use crate::tls::BoxedStream;
+
+// Bytes a peer may send on one connection before it has to wait for a
+// WindowUpdate. The receiver hands credit back once a quarter of the window
+// has been written to the local socket.
+pub const INITIAL_WINDOW: u32 = 256 * 1024;
+const WINDOW_UPDATE_THRESHOLD: u32 = INITIAL_WINDOW / 4;
+
+const READ_BUF_SIZE: usize = 32768;
+
+// Both peers open connections on the same control connection: the client for
+// local forwards and the server for reverse forwards. Server-assigned ids start
+// here so the two never collide.
+pub const SERVER_CONN_ID_BASE: u32 = 1 << 31;
+
+// Hands out connection ids from one side's range, wrapping around at its end
+// and skipping ids still in use, so a long-lived client never strays into the
+// server's range or reuses a live id.
+pub struct ConnIds {
+    next: AtomicU32,
+    first: u32,
+    last: u32,
+}
+
+impl ConnIds {
+    pub fn client() -> Self {
+        ConnIds::new(1, SERVER_CONN_ID_BASE - 1)
+    }
+
+    pub fn server() -> Self {
+        ConnIds::new(SERVER_CONN_ID_BASE, u32::MAX)
+    }
+
+    fn new(first: u32, last: u32) -> Self {
+        ConnIds {
+            next: AtomicU32::new(first),
+            first,
+            last,
+        }
+    }
+
+    pub fn next(&self, in_use: impl Fn(u32) -> bool) -> u32 {
+        loop {
+            let step = |id: u32| Some(if id >= self.last { self.first } else { id + 1 });
+            let id = self.next.fetch_update(Ordering::Relaxed, Ordering::Relaxed, step).unwrap();
+            if !in_use(id) {
+                return id;
+            }
+        }
+    }
+}
+
+// Each direction of a connection ends on its own: a side that hits EOF on
+// its socket sends Fin after its last Data, and the other side shuts down the
+// write half of its socket once that Data is written. CloseConnection ends
+// both directions at once: the socket is dropped without waiting for either.
+struct Connection {
+    // None once the peer has sent Fin.
+    data_tx: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    // Bytes routed to the writer task and not yet written to the socket. A
+    // well-behaved peer keeps this within INITIAL_WINDOW.
+    queued: Arc<AtomicU32>,
+    send_window: Arc<Semaphore>,
+    // Wakes the connection's `read_loop` once the entry is gone.
+    closed: Arc<Notify>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.send_window.close();
+        self.closed.notify_one();
+    }
+}
+
+// Routing table for the connections multiplexed on one control connection.
+// The lock is only ever held to look up or update an entry, never across an
+// await, so the frame dispatch loop cannot be stalled by a slow socket.
+//
+// Over QUIC, connections get streams of their own and are never in the map.
+pub struct Connections {
+    inner: Mutex<HashMap<u32, Connection>>,
+    quic: Option<quinn::Connection>,
+    // Whether the peer understands Fin. Without it, EOF on a socket closes
+    // the whole connection.
+    half_close: bool,
+    // Connections that haven't finished running, QUIC ones and those still
+    // being dialed included, for a session draining on shutdown.
+    live: Arc<watch::Sender<usize>>,
+}
+
+// A connection that has no socket yet. Data frames that arrive while the
+// socket is being dialed wait in its queue, or in its QUIC stream.
+pub struct PendingConnection {
+    conn_id: u32,
+    carrier: Carrier,
+    _live: LiveGuard,
+}
+
+// Counts a connection as live until dropped.
+struct LiveGuard(Arc<watch::Sender<usize>>);
+
+impl LiveGuard {
+    fn new(live: &Arc<watch::Sender<usize>>) -> Self {
+        live.send_modify(|count| *count += 1);
+        LiveGuard(live.clone())
+    }
+}
+
+impl Drop for LiveGuard {
+    fn drop(&mut self) {
+        self.0.send_modify(|count| *count -= 1);
+    }
+}
+
+enum Carrier {
+    Frames {
+        data_rx: mpsc::UnboundedReceiver<Vec<u8>>,
+        queued: Arc<AtomicU32>,
+        send_window: Arc<Semaphore>,
+        closed: Arc<Notify>,
+        connections: Arc<Connections>,
+        tx: FrameSender,
+    },
+    Stream(BoxedStream),
+}
+
+impl Connections {
+    pub fn new(quic: Option<quinn::Connection>, half_close: bool) -> Self {
+        Connections {
+            inner: Mutex::default(),
+            quic,
+            half_close,
+            live: Arc::new(watch::Sender::new(0)),
+        }
+    }
+
+    // Sets up a connection this side opens and tells the peer about it, with
+    // `target` as the NewConnection payload.
+    pub async fn open(
+        self: &Arc<Self>,
+        conn_id: u32,
+        target: Vec<u8>,
+        tx: &FrameSender,
+    ) -> anyhow::Result<PendingConnection> {
+        let frame = Frame {
+            frame_type: FrameType::NewConnection,
+            conn_id,
+            data: target,
+        };
+        if let Some(quic) = &self.quic {
+            let stream = quic::open_stream(quic, &frame).await?;
+            return Ok(PendingConnection {
+                conn_id,
+                carrier: Carrier::Stream(stream),
+                _live: LiveGuard::new(&self.live),
+            });
+        }
+
+        let pending = self
+            .register(conn_id, tx)
+            .ok_or_else(|| anyhow::anyhow!("Connection id {} already in use", conn_id))?;
+        if let Err(e) = tx.send_control(frame) {
+            self.remove(conn_id);
+            return Err(e);
+        }
+        Ok(pending)
+    }
+
+    // Sets up a connection the peer opened, on the QUIC stream it came with
+    // if there is one. A peer reusing the id of a live connection is told
+    // off, and the live connection is left alone.
+    pub fn accept(
+        self: &Arc<Self>,
+        conn_id: u32,
+        tx: &FrameSender,
+        stream: Option<BoxedStream>,
+    ) -> Option<PendingConnection> {
+        if let Some(stream) = stream {
+            return Some(PendingConnection {
+                conn_id,
+                carrier: Carrier::Stream(stream),
+                _live: LiveGuard::new(&self.live),
+            });
+        }
+        let pending = self.register(conn_id, tx);
+        if pending.is_none() {
+            warn!("Connection {} opened twice", conn_id);
+            send_close(tx, conn_id, CloseReason::ProtocolError, "connection id in use");
+        }
+        pending
+    }
+
+    // Returns None if `conn_id` is already taken.
+    fn register(self: &Arc<Self>, conn_id: u32, tx: &FrameSender) -> Option<PendingConnection> {
+        let mut conns = self.inner.lock().unwrap();
+        if conns.contains_key(&conn_id) {
+            return None;
+        }
+        let (data_tx, data_rx) = mpsc::unbounded_channel();
+        let queued = Arc::new(AtomicU32::new(0));
+        let send_window = Arc::new(Semaphore::new(INITIAL_WINDOW as usize));
+        let closed = Arc::new(Notify::new());
+        let conn = Connection {
+            data_tx: Some(data_tx),
+            queued: queued.clone(),
+            send_window: send_window.clone(),
+            closed: closed.clone(),
+        };
+        conns.insert(conn_id, conn);
+        drop(conns);
+
+        Some(PendingConnection {
+            conn_id,
+            carrier: Carrier::Frames {
+                data_rx,
+                queued,
+                send_window,
+                closed,
+                connections: self.clone(),
+                tx: tx.clone(),
+            },
+            _live: LiveGuard::new(&self.live),
+        })
+    }
+
+    pub fn contains(&self, conn_id: u32) -> bool {
+        self.inner.lock().unwrap().contains_key(&conn_id)
+    }
+
+    // Returns whether the connection was still there.
+    pub fn remove(&self, conn_id: u32) -> bool {
+        self.inner.lock().unwrap().remove(&conn_id).is_some()
+    }
+
+    // Ends a connection once `run` has returned, telling the peer behind any
+    // Data still queued for it. A connection that was already removed was
+    // closed by the peer, a write error or the end of the session, and the
+    // peer knows.
+    pub async fn finish(&self, tx: &FrameSender, conn_id: u32, reason: CloseReason) {
+        let open = self.remove(conn_id) || self.quic.is_some();
+        if reason == CloseReason::Normal {
+            info!("Connection {} closed", conn_id);
+        } else {
+            info!("Connection {} closed: {}", conn_id, reason.description());
+        }
+        if open {
+            count_close(&CLOSE_STATS.sent, reason);
+            let _ = tx.send(Frame::close(conn_id, reason, "")).await;
+        }
+    }
+
+    pub fn live(&self) -> usize {
+        *self.live.borrow()
+    }
+
+    // Resolves once no connection is live.
+    pub async fn drained(&self) {
+        let _ = self.live.subscribe().wait_for(|count| *count == 0).await;
+    }
+
+    // Ends every connection when the session is over.
+    pub fn clear(&self) {
+        self.inner.lock().unwrap().clear();
+        if let Some(quic) = &self.quic {
+            quic.close(VarInt::from_u32(0), b"session ended");
+        }
+    }
+
+    // Hands a Data frame to the connection's writer task. A peer that sends
+    // past the window it was granted gets the connection closed rather than
+    // growing the queue.
+    pub fn route_data(&self, tx: &FrameSender, frame: Frame) {
+        let conn_id = frame.conn_id;
+        let len = frame.data.len() as u32;
+
+        let mut conns = self.inner.lock().unwrap();
+        let Some(conn) = conns.get(&conn_id) else {
+            return;
+        };
+
+        let queued = conn.queued.fetch_add(len, Ordering::AcqRel) + len;
+        let problem = match &conn.data_tx {
+            None => "sent data after Fin",
+            Some(_) if queued > INITIAL_WINDOW => "exceeded its receive window",
+            Some(data_tx) => {
+                // Fails only if a write error already closed the connection.
+                let _ = data_tx.send(frame.data);
+                return;
+            }
+        };
+
+        conns.remove(&conn_id);
+        drop(conns);
+        warn!("Connection {} {}", conn_id, problem);
+        send_close(tx, conn_id, CloseReason::ProtocolError, problem);
+    }
+
+    // The peer is done sending on the connection: the socket's write half is
+    // shut down once everything already routed to it has been written.
+    pub fn handle_fin(&self, conn_id: u32) {
+        if let Some(conn) = self.inner.lock().unwrap().get_mut(&conn_id) {
+            conn.data_tx = None;
+        }
+    }
+
+    // Credits the connection's send window. The peer can only hand back what
+    // was sent to it, so an update that would grow the window past
+    // INITIAL_WINDOW closes the connection instead.
+    pub fn handle_window_update(&self, tx: &FrameSender, frame: Frame) {
+        let conn_id = frame.conn_id;
+        if frame.data.len() < 4 {
+            warn!("Invalid WindowUpdate frame for connection {}", conn_id);
+            return;
+        }
+        let increment = u32::from_be_bytes([
+            frame.data[0],
+            frame.data[1],
+            frame.data[2],
+            frame.data[3],
+        ]);
+
+        let mut conns = self.inner.lock().unwrap();
+        let Some(conn) = conns.get(&conn_id) else {
+            return;
+        };
+        let available = conn.send_window.available_permits() as u64;
+        if available + increment as u64 <= INITIAL_WINDOW as u64 {
+            conn.send_window.add_permits(increment as usize);
+            return;
+        }
+
+        conns.remove(&conn_id);
+        drop(conns);
+        let problem = "granted more than its receive window";
+        warn!("Connection {} {}", conn_id, problem);
+        send_close(tx, conn_id, CloseReason::ProtocolError, problem);
+    }
+}
+
+impl PendingConnection {
+    // Attaches the socket and relays in both directions. Returns once both
+    // directions are done, or as soon as reading from the socket fails, with
+    // the reason for the caller to `finish` the connection with. QUIC streams
+    // carry half-closes natively.
+    pub async fn run<S>(self, stream: S) -> CloseReason
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        self.run_with_initial_data(stream, Vec::new()).await
+    }
+
+    // Like `run`, but first sends `initial` to the peer as if it had been read
+    // from the socket, for bytes a local proxy handshake already consumed.
+    pub async fn run_with_initial_data<S>(self, mut stream: S, initial: Vec<u8>) -> CloseReason
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let (data_rx, queued, send_window, closed, connections, tx) = match self.carrier {
+            Carrier::Frames {
+                data_rx,
+                queued,
+                send_window,
+                closed,
+                connections,
+                tx,
+            } => (data_rx, queued, send_window, closed, connections, tx),
+            Carrier::Stream(mut tunnel) => {
+                let relayed = match tunnel.write_all(&initial).await {
+                    Ok(()) => tokio::io::copy_bidirectional(&mut stream, &mut tunnel).await,
+                    Err(e) => Err(e),
+                };
+                return match relayed {
+                    Ok(_) => CloseReason::Normal,
+                    Err(e) => io_close_reason(&e),
+                };
+            }
+        };
+
+        if send_window.is_closed() {
+            // Closed by the peer before the socket was ready.
+            return CloseReason::Normal;
+        }
+
+        let half_close = connections.half_close;
+        let (read_half, write_half) = tokio::io::split(stream);
+        let conn_id = self.conn_id;
+        let writer = write_loop(write_half, conn_id, data_rx, queued, connections, tx.clone());
+        let writer = tokio::spawn(writer.in_current_span());
+
+        let read = read_loop(read_half, conn_id, send_window, &closed, &tx, initial, half_close);
+        match read.await {
+            Ok(ReadEnd::Fin) => {
+                // Only our direction is done; the peer may still be sending.
+                let _ = writer.await;
+                CloseReason::Normal
+            }
+            Ok(ReadEnd::Done) => CloseReason::Normal,
+            Ok(ReadEnd::Closed) => {
+                // Drops the socket along with whatever was left to write.
+                writer.abort();
+                CloseReason::Normal
+            }
+            Err(e) => {
+                warn!("Read from connection {} error: {}", conn_id, e);
+                io_close_reason(&e)
+            }
+        }
+    }
+}
+
+async fn write_loop<S: AsyncWrite>(
+    mut writer: WriteHalf<S>,
+    conn_id: u32,
+    mut data_rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    queued: Arc<AtomicU32>,
+    connections: Arc<Connections>,
+    tx: FrameSender,
+) {
+    let mut unacked: u32 = 0;
+    while let Some(data) = data_rx.recv().await {
+        if let Err(e) = writer.write_all(&data).await {
+            warn!("Write to connection {} error: {}", conn_id, e);
+            connections.remove(conn_id);
+            info!("Connection {} closed (write error)", conn_id);
+            send_close(&tx, conn_id, io_close_reason(&e), &e.to_string());
+            return;
+        }
+
+        let len = data.len() as u32;
+        queued.fetch_sub(len, Ordering::AcqRel);
+        unacked += len;
+        if unacked >= WINDOW_UPDATE_THRESHOLD {
+            let update = Frame {
+                frame_type: FrameType::WindowUpdate,
+                conn_id,
+                data: unacked.to_be_bytes().to_vec(),
+            };
+            unacked = 0;
+            let _ = tx.send_control(update);
+        }
+    }
+
+    // The peer sent Fin, the connection was removed from the map (closed by
+    // the peer or the session ended), and everything queued for it has been
+    // written.
+    let _ = writer.shutdown().await;
+}
+
+// Connections closed since startup, by reason, as told to the peer and as
+// heard from it. Logged when a session ends.
+struct CloseStats {
+    sent: [AtomicU64; CloseReason::ALL.len()],
+    received: [AtomicU64; CloseReason::ALL.len()],
+}
+
+static CLOSE_STATS: CloseStats = CloseStats {
+    sent: [const { AtomicU64::new(0) }; CloseReason::ALL.len()],
+    received: [const { AtomicU64::new(0) }; CloseReason::ALL.len()],
+};
+
+fn count_close(counts: &[AtomicU64], reason: CloseReason) {
+    counts[reason as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+// Summary of CLOSE_STATS, e.g. "sent 12 closed, 1 timed out; received 13
+// closed".
+pub fn close_stats() -> String {
+    let summarize = |counts: &[AtomicU64]| {
+        let counts: Vec<String> = CloseReason::ALL
+            .iter()
+            .filter_map(|&reason| match counts[reason as usize].load(Ordering::Relaxed) {
+                0 => None,
+                n => Some(format!("{} {}", n, reason.description())),
+            })
+            .collect();
+        if counts.is_empty() {
+            "none".to_string()
+        } else {
+            counts.join(", ")
+        }
+    };
+    format!(
+        "sent {}; received {}",
+        summarize(&CLOSE_STATS.sent),
+        summarize(&CLOSE_STATS.received)
+    )
+}
+
+// Tells the peer a connection is over, ahead of any Data still queued. The
+// message is for the peer's logs.
+pub fn send_close(tx: &FrameSender, conn_id: u32, reason: CloseReason, message: &str) {
+    count_close(&CLOSE_STATS.sent, reason);
+    let _ = tx.send_control(Frame::close(conn_id, reason, message));
+}
+
+// Reads the reason off a CloseConnection frame from the peer.
+pub fn received_close(frame: &Frame) -> (CloseReason, String) {
+    let (reason, message) = frame.close_reason();
+    count_close(&CLOSE_STATS.received, reason);
+    (reason, message)
+}
+
+// The close reason for a failed connect or socket error.
+pub fn io_close_reason(e: &io::Error) -> CloseReason {
+    match e.kind() {
+        io::ErrorKind::ConnectionRefused => CloseReason::ConnectRefused,
+        io::ErrorKind::TimedOut => CloseReason::Timeout,
+        io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::BrokenPipe => CloseReason::PeerReset,
+        _ => CloseReason::ConnectFailed,
+    }
+}
+
+// Reads frames off the control connection into a channel, so the dispatch
+// loop can wait on other things too without dropping a half-read frame. The
+// error that ends the connection is the last item.
+pub fn spawn_reader<R>(
+    mut reader: R,
+    mut cipher: FrameCipher,
+) -> (mpsc::Receiver<anyhow::Result<Frame>>, JoinHandle<()>)
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let (frame_tx, frame_rx) = mpsc::channel(256);
+    let task = async move {
+        loop {
+            let result = tokio::select! {
+                result = protocol::read_frame(&mut reader, &mut cipher) => result,
+                _ = frame_tx.closed() => break,
+            };
+            let failed = result.is_err();
+            if frame_tx.send(result).await.is_err() || failed {
+                break;
+            }
+        }
+    };
+    (frame_rx, tokio::spawn(task.in_current_span()))
+}
+
+pub fn spawn_writer<W>(
+    mut writer: W,
+    mut cipher: FrameCipher,
+    mut rx: FrameReceiver,
+) -> JoinHandle<()>
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let task = async move {
+        while let Some(frame) = rx.recv().await {
+            if let Err(e) = protocol::write_frame(&mut writer, &mut cipher, &frame).await {
+                error!("Control write error: {}", e);
+                break;
+            }
+        }
+    };
+    tokio::spawn(task.in_current_span())
+}
+
+// How `read_loop` ended, short of a read error.
+enum ReadEnd {
+    // The socket hit EOF and Fin was sent; the other direction goes on.
+    Fin,
+    // The socket hit EOF and the peer can't take a Fin, or the control
+    // connection went away.
+    Done,
+    // The connection was removed: closed by the peer, a write error or the
+    // end of the session.
+    Closed,
+}
+
+// Forwards bytes read from the socket as Data frames, waiting for send credit
+// before each frame so a slow peer backpressures this socket.
+async fn read_loop<S: AsyncRead>(
+    mut reader: ReadHalf<S>,
+    conn_id: u32,
+    send_window: Arc<Semaphore>,
+    closed: &Notify,
+    tx: &FrameSender,
+    initial: Vec<u8>,
+    half_close: bool,
+) -> io::Result<ReadEnd> {
+    for chunk in initial.chunks(READ_BUF_SIZE) {
+        if !send_data(conn_id, &send_window, tx, chunk).await {
+            return Ok(ReadEnd::Closed);
+        }
+    }
+
+    let mut buf = vec![0u8; READ_BUF_SIZE];
+    loop {
+        let n = tokio::select! {
+            result = reader.read(&mut buf) => match result? {
+                0 => break,
+                n => n,
+            },
+            () = closed.notified() => return Ok(ReadEnd::Closed),
+            _ = tx.closed() => return Ok(ReadEnd::Done),
+        };
+
+        if !send_data(conn_id, &send_window, tx, &buf[..n]).await {
+            return Ok(ReadEnd::Closed);
+        }
+    }
+
+    if !half_close {
+        return Ok(ReadEnd::Done);
+    }
+    // Queued behind the Data frames so it arrives after them.
+    let fin_frame = Frame {
+        frame_type: FrameType::Fin,
+        conn_id,
+        data: vec![],
+    };
+    match tx.send(fin_frame).await {
+        Ok(()) => Ok(ReadEnd::Fin),
+        Err(_) => Ok(ReadEnd::Done),
+    }
+}
+
+// Sends one Data frame once the peer has granted credit for it. Returns false
+// if the connection or the control connection is gone.
+async fn send_data(conn_id: u32, send_window: &Semaphore, tx: &FrameSender, data: &[u8]) -> bool {
+    let permit = tokio::select! {
+        permit = send_window.acquire_many(data.len() as u32) => match permit {
+            Ok(permit) => permit,
+            Err(_) => return false,
+        },
+        _ = tx.closed() => return false,
+    };
+    permit.forget();
+
+    let frame = Frame {
+        frame_type: FrameType::Data,
+        conn_id,
+        data: data.to_vec(),
+    };
+    tx.send(frame).await.is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn duplicate_conn_id_is_refused() {
+        let (tx, mut rx) = protocol::frame_channel();
+        let connections = Arc::new(Connections::new(None, true));
+
+        let first = connections.accept(7, &tx, None);
+        assert!(first.is_some());
+        assert!(connections.accept(7, &tx, None).is_none());
+
+        let close = rx.recv().await.unwrap();
+        assert!(matches!(close.frame_type, FrameType::CloseConnection));
+        assert_eq!(close.conn_id, 7);
+        assert_eq!(close.close_reason().0, CloseReason::ProtocolError);
+        // The first connection is still registered.
+        assert!(connections.remove(7));
+    }
+
+    #[test]
+    fn conn_ids_wrap_within_their_range_and_skip_live_ids() {
+        let ids = ConnIds::client();
+        ids.next.store(SERVER_CONN_ID_BASE - 2, Ordering::Relaxed);
+        assert_eq!(ids.next(|_| false), SERVER_CONN_ID_BASE - 2);
+        assert_eq!(ids.next(|_| false), SERVER_CONN_ID_BASE - 1);
+        assert_eq!(ids.next(|id| id == 1 || id == 2), 3);
+
+        let ids = ConnIds::server();
+        ids.next.store(u32::MAX, Ordering::Relaxed);
+        assert_eq!(ids.next(|_| false), u32::MAX);
+        assert_eq!(ids.next(|_| false), SERVER_CONN_ID_BASE);
+    }
+}
@@ -0,0 +1,74 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::net::UdpSocket;
+
+use crate::protocol::{Frame, FrameSender, FrameType};
+
+pub const MAX_DATAGRAM_SIZE: usize = 65535;
+
+// A UDP flow is one local peer talking to one target. It shares the
+// connection id space with TCP connections, is opened with NewUdpFlow (same
+// payload as NewConnection), carries UdpDatagram frames and ends with
+// CloseConnection, sent by whichever side expires it first.
+
+// When a flow last carried a datagram in either direction.
+pub struct Activity {
+    start: Instant,
+    last_ms: AtomicU64,
+}
+
+impl Activity {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Activity {
+            start: Instant::now(),
+            last_ms: AtomicU64::new(0),
+        })
+    }
+
+    pub fn touch(&self) {
+        let now = self.start.elapsed().as_millis() as u64;
+        self.last_ms.store(now, Ordering::Relaxed);
+    }
+
+    pub fn idle_for(&self) -> Duration {
+        let last = Duration::from_millis(self.last_ms.load(Ordering::Relaxed));
+        self.start.elapsed().saturating_sub(last)
+    }
+}
+
+// The local end of a flow on the side that accepted it: datagrams from the
+// tunnel are sent to `peer` from `socket`, prefixed with `header`.
+pub struct LocalReturn {
+    pub socket: Arc<UdpSocket>,
+    pub peer: SocketAddr,
+    pub header: Vec<u8>,
+    pub activity: Arc<Activity>,
+}
+
+impl LocalReturn {
+    // Never waits: a datagram the socket cannot take right now is dropped.
+    pub fn deliver(&self, payload: &[u8]) {
+        self.activity.touch();
+        let result = if self.header.is_empty() {
+            self.socket.try_send_to(payload, self.peer)
+        } else {
+            let mut datagram = Vec::with_capacity(self.header.len() + payload.len());
+            datagram.extend_from_slice(&self.header);
+            datagram.extend_from_slice(payload);
+            self.socket.try_send_to(&datagram, self.peer)
+        };
+        let _ = result;
+    }
+}
+
+pub fn send_datagram(tx: &FrameSender, flow_id: u32, payload: &[u8]) {
+    let frame = Frame {
+        frame_type: FrameType::UdpDatagram,
+        conn_id: flow_id,
+        data: payload.to_vec(),
+    };
+    let _ = tx.try_send(frame);
+}
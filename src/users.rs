@@ -0,0 +1,113 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::acl::Acl;
+use crate::config::ServerConfig;
+use crate::crypto;
+
+pub struct User {
+    pub name: String,
+    pub psk: [u8; 32],
+    pub acl: Acl,
+    // Where the user's reverse forwards may listen, on top of the global
+    // [bind_acl].
+    pub bind_acl: Acl,
+    pub forwards: Quota,
+    pub connections: Quota,
+}
+
+// Counts a per-user resource across all of that user's sessions.
+pub struct Quota {
+    count: Arc<AtomicUsize>,
+    max: Option<usize>,
+}
+
+pub struct QuotaGuard {
+    count: Arc<AtomicUsize>,
+}
+
+impl Quota {
+    // Continues the count of `previous`, the same user's quota before a
+    // reload, so what the user already holds still counts.
+    fn new(max: Option<usize>, previous: Option<&Quota>) -> Self {
+        Quota {
+            count: previous.map_or_else(Default::default, |quota| quota.count.clone()),
+            max,
+        }
+    }
+
+    pub fn acquire(&self) -> Option<QuotaGuard> {
+        let max = self.max.unwrap_or(usize::MAX);
+        self.count
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < max).then_some(n + 1)
+            })
+            .ok()?;
+        Some(QuotaGuard {
+            count: self.count.clone(),
+        })
+    }
+}
+
+impl Drop for QuotaGuard {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+// The top-level `token`, if set, becomes a user named "default" with no
+// limits of its own. `previous` are the users before a reload, whose quotas
+// carry over by name.
+pub fn load_users(
+    config: &ServerConfig,
+    previous: &[Arc<User>],
+) -> anyhow::Result<Vec<Arc<User>>> {
+    let mut users = Vec::new();
+    let previous = |name: &str| previous.iter().find(|user| user.name == name);
+
+    if let Some(token) = &config.token {
+        let old = previous("default");
+        users.push(Arc::new(User {
+            name: "default".to_string(),
+            psk: crypto::derive_psk(token),
+            acl: Acl::from_config(&Default::default())?,
+            bind_acl: Acl::from_config(&Default::default())?,
+            forwards: Quota::new(None, old.map(|user| &user.forwards)),
+            connections: Quota::new(None, old.map(|user| &user.connections)),
+        }));
+    }
+
+    for user in &config.users {
+        let old = previous(&user.name);
+        users.push(Arc::new(User {
+            name: user.name.clone(),
+            psk: crypto::derive_psk(&user.token),
+            acl: Acl::from_config(&user.acl)
+                .map_err(|e| anyhow::anyhow!("User {}: {}", user.name, e))?,
+            bind_acl: Acl::from_config(&user.bind_acl)
+                .map_err(|e| anyhow::anyhow!("User {}: {}", user.name, e))?,
+            forwards: Quota::new(user.max_forwards, old.map(|user| &user.forwards)),
+            connections: Quota::new(user.max_connections, old.map(|user| &user.connections)),
+        }));
+    }
+
+    if users.is_empty() {
+        return Err(anyhow::anyhow!(
+            "No credentials configured: set `token` or add [[users]]"
+        ));
+    }
+
+    let mut names = HashSet::new();
+    let mut psks = HashSet::new();
+    for user in &users {
+        if !names.insert(user.name.as_str()) {
+            return Err(anyhow::anyhow!("Duplicate user name: {}", user.name));
+        }
+        if !psks.insert(user.psk) {
+            return Err(anyhow::anyhow!("User {} reuses another user's token", user.name));
+        }
+    }
+
+    Ok(users)
+}
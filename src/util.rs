@@ -0,0 +1,28 @@
+use std::io;
+use std::time::Duration;
+
+use tracing::error;
+
+// How long a listener waits before accepting again after an error such as
+// running out of file descriptors.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+// Accept errors don't end a listener. One about a peer that gave up before
+// it was accepted is skipped; others, such as running out of file
+// descriptors, are logged with `label`, naming the listener, and retried
+// after a pause.
+pub async fn accept_failed(label: &str, e: io::Error) {
+    match e.kind() {
+        io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::Interrupted => {}
+        _ => {
+            error!(
+                "Accept error on {}: {}, retrying in {:?}",
+                label, e, ACCEPT_RETRY_DELAY
+            );
+            tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+        }
+    }
+}
@@ -0,0 +1,214 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+
+use futures_util::{Sink, Stream as _};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::tungstenite::{Bytes, Message};
+use tracing::info;
+
+use crate::config::ServerWebSocketConfig;
+use crate::tls::BoxedStream;
+
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+const DEFAULT_DECOY_PAGE: &str = "<!DOCTYPE html>\n<html>\n<head><title>Welcome</title></head>\n\
+<body>\n<h1>It works!</h1>\n</body>\n</html>\n";
+
+// Carries the control connection's byte stream in binary WebSocket messages.
+// Writes are collected until flush, and `write_frame` flushes after every
+// frame, so each message holds exactly one length-prefixed frame.
+struct WsStream {
+    inner: WebSocketStream<BoxedStream>,
+    read_buf: Bytes,
+    write_buf: Vec<u8>,
+}
+
+impl WsStream {
+    fn new(inner: WebSocketStream<BoxedStream>) -> Self {
+        WsStream {
+            inner,
+            read_buf: Bytes::new(),
+            write_buf: Vec::new(),
+        }
+    }
+}
+
+fn ws_error(e: tokio_tungstenite::tungstenite::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+impl AsyncRead for WsStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.read_buf.is_empty() {
+            match ready!(Pin::new(&mut self.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => self.read_buf = data,
+                // Pings are answered by tungstenite itself.
+                Some(Ok(Message::Ping(_) | Message::Pong(_))) => {}
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Ok(_)) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Unexpected non-binary WebSocket message",
+                    )));
+                }
+                Some(Err(e)) => return Poll::Ready(Err(ws_error(e))),
+            }
+        }
+        let n = self.read_buf.len().min(buf.remaining());
+        let chunk = self.read_buf.split_to(n);
+        buf.put_slice(&chunk);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for WsStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.write_buf.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.write_buf.is_empty() {
+            ready!(Pin::new(&mut self.inner).poll_ready(cx)).map_err(ws_error)?;
+            let data = std::mem::take(&mut self.write_buf);
+            Pin::new(&mut self.inner)
+                .start_send(Message::Binary(data.into()))
+                .map_err(ws_error)?;
+        }
+        Pin::new(&mut self.inner).poll_flush(cx).map_err(ws_error)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        Pin::new(&mut self.inner).poll_close(cx).map_err(ws_error)
+    }
+}
+
+// Performs the client side of the upgrade for `url` (ws:// or wss://; the
+// scheme only affects the request, TLS is already in place on `stream`).
+pub async fn connect(stream: BoxedStream, url: &str) -> anyhow::Result<BoxedStream> {
+    let (ws, _) = tokio_tungstenite::client_async(url, stream)
+        .await
+        .map_err(|e| anyhow::anyhow!("WebSocket upgrade to {} failed: {}", url, e))?;
+    Ok(Box::new(WsStream::new(ws)))
+}
+
+// The server side: upgrade requests for `path` become tunnel connections,
+// anything else gets the decoy page, as an ordinary web server would answer.
+pub struct Endpoint {
+    path: String,
+    decoy_page: Vec<u8>,
+}
+
+impl Endpoint {
+    pub fn from_config(config: &ServerWebSocketConfig) -> anyhow::Result<Self> {
+        let decoy_page = match &config.decoy_file {
+            Some(path) => std::fs::read(path)
+                .map_err(|e| anyhow::anyhow!("Failed to read decoy page {}: {}", path, e))?,
+            None => DEFAULT_DECOY_PAGE.as_bytes().to_vec(),
+        };
+        Ok(Endpoint {
+            path: config.path.clone(),
+            decoy_page,
+        })
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        path.split('?').next() == Some(self.path.as_str())
+    }
+
+    // Returns None once a non-tunnel request has been answered.
+    pub async fn accept(&self, mut stream: BoxedStream) -> anyhow::Result<Option<BoxedStream>> {
+        let mut buf = Vec::with_capacity(1024);
+        let mut chunk = [0u8; 4096];
+        let (head_len, method, path, key, upgrade) = loop {
+            let n = stream.read(&mut chunk).await?;
+            if n == 0 {
+                return Err(anyhow::anyhow!("Connection closed before request head"));
+            }
+            buf.extend_from_slice(&chunk[..n]);
+
+            let mut parsed_headers = [httparse::EMPTY_HEADER; 64];
+            let mut request = httparse::Request::new(&mut parsed_headers);
+            match request.parse(&buf) {
+                Ok(httparse::Status::Complete(len)) => {
+                    let header = |name: &str| {
+                        request
+                            .headers
+                            .iter()
+                            .find(|h| h.name.eq_ignore_ascii_case(name))
+                            .map(|h| h.value.to_vec())
+                    };
+                    let key = header("sec-websocket-key");
+                    let upgrade = header("upgrade")
+                        .is_some_and(|value| value.eq_ignore_ascii_case(b"websocket"));
+                    let method = request.method.unwrap_or_default().to_string();
+                    let path = request.path.unwrap_or_default().to_string();
+                    break (len, method, path, key, upgrade);
+                }
+                Ok(httparse::Status::Partial) if buf.len() < MAX_HEAD_SIZE => continue,
+                Ok(httparse::Status::Partial) | Err(_) => {
+                    write_response(&mut stream, "400 Bad Request", &[], b"").await?;
+                    return Err(anyhow::anyhow!("Invalid HTTP request"));
+                }
+            }
+        };
+
+        let key = match key {
+            Some(key) if upgrade && method == "GET" && self.matches(&path) => key,
+            _ => {
+                info!("Serving decoy page for {} {}", method, path);
+                let body: &[u8] = if method == "HEAD" { b"" } else { &self.decoy_page };
+                let content_length = format!("Content-Length: {}", self.decoy_page.len());
+                let headers = ["Content-Type: text/html", content_length.as_str()];
+                write_response(&mut stream, "200 OK", &headers, body).await?;
+                return Ok(None);
+            }
+        };
+
+        let accept = format!("Sec-WebSocket-Accept: {}", derive_accept_key(&key));
+        let headers = ["Upgrade: websocket", "Connection: Upgrade", accept.as_str()];
+        write_response(&mut stream, "101 Switching Protocols", &headers, b"").await?;
+
+        let leftover = buf[head_len..].to_vec();
+        let ws = WebSocketStream::from_partially_read(stream, leftover, Role::Server, None).await;
+        Ok(Some(Box::new(WsStream::new(ws))))
+    }
+}
+
+async fn write_response(
+    stream: &mut BoxedStream,
+    status: &str,
+    headers: &[&str],
+    body: &[u8],
+) -> anyhow::Result<()> {
+    let mut response = format!("HTTP/1.1 {}\r\n", status);
+    for header in headers {
+        response.push_str(header);
+        response.push_str("\r\n");
+    }
+    if !status.starts_with("101") {
+        if body.is_empty() && !headers.iter().any(|h| h.starts_with("Content-Length")) {
+            response.push_str("Content-Length: 0\r\n");
+        }
+        response.push_str("Connection: close\r\n");
+    }
+    response.push_str("\r\n");
+    let mut response = response.into_bytes();
+    response.extend_from_slice(body);
+    stream.write_all(&response).await?;
+    stream.flush().await?;
+    Ok(())
+}